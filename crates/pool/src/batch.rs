@@ -0,0 +1,136 @@
+use wasm_mt::debug_ln;
+use wasm_mt::utils::sleep;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::spawn_local;
+use js_sys::{Array, ArrayBuffer, Date};
+use std::rc::Rc;
+use super::{ResultJJ, ThreadPoolInner, JobState, JobStatus};
+
+pub(crate) struct Member {
+    ab: ArrayBuffer,
+    job: Rc<JobState>,
+    cb: Box<dyn FnOnce(ResultJJ)>,
+}
+
+impl Member {
+    pub fn new(ab: ArrayBuffer, job: Rc<JobState>, cb: Box<dyn FnOnce(ResultJJ)>) -> Self {
+        Self { ab, job, cb }
+    }
+}
+
+// Decodes an `[isOk, result, ms]` triple
+fn decode_triple(jsv: &JsValue) -> (ResultJJ, f64) {
+    let triple = jsv.unchecked_ref::<Array>();
+    let result = if triple.get(0).as_bool().unwrap_or(false) { Ok(triple.get(1)) } else { Err(triple.get(1)) };
+    (result, triple.get(2).as_f64().unwrap_or(0.0))
+}
+
+impl ThreadPoolInner {
+    // A batch is sent once it is full, or else after the current burst of submissions
+    pub(crate) fn push_batch(self: &Rc<Self>, member: Member) {
+        let len = {
+            let mut batch = self.batch.borrow_mut();
+            batch.push(member);
+            batch.len()
+        };
+
+        if len >= self.batch_size.borrow().unwrap_or(1) {
+            let members = self.batch.replace(Vec::new());
+            let inner = self.clone();
+            spawn_local(async move { inner.run_batch(members).await });
+        } else if len == 1 {
+            let inner = self.clone();
+            spawn_local(async move {
+                let members = inner.batch.replace(Vec::new());
+                inner.run_batch(members).await
+            });
+        }
+    }
+
+    // The members share one thread and message, but are otherwise accounted
+    // for as jobs of their own, including the stats and retries
+    async fn run_batch(self: &Rc<Self>, members: Vec<Member>) {
+        let mut live = Vec::with_capacity(members.len());
+        for member in members {
+            self.resolver.leave_waiting();
+            if member.job.is_canceled() {
+                self.stats.record_outcome(None);
+                (member.cb)(Err(member.job.canceled_error()));
+            } else {
+                live.push(member);
+            }
+        }
+        if live.is_empty() {
+            return;
+        }
+
+        let batch = self.new_job(false);
+        let pth = match self.acquire_thread(&batch).await {
+            Ok(pth) => pth,
+            Err(jsv) => {
+                for member in live {
+                    self.stats.record_outcome(Some(false));
+                    (member.cb)(Err(jsv.clone()));
+                }
+                return;
+            },
+        };
+
+        // Canceled while waiting for the thread
+        let (canceled, live): (Vec<_>, Vec<_>) = live.into_iter().partition(|member| member.job.is_canceled());
+        for member in canceled {
+            self.stats.record_outcome(None);
+            (member.cb)(Err(member.job.canceled_error()));
+        }
+        if live.is_empty() {
+            self.release_thread(&pth, false);
+            return;
+        }
+
+        let thread_id = pth.get_id().unwrap().to_string();
+        let now = Date::now();
+        let attempts: Vec<_> = live.iter().map(|member| {
+            member.job.set_sent(true);
+            self.stats.record_wait(now - member.job.submitted_at());
+            member.job.start_attempt(&thread_id)
+        }).collect();
+
+        // copied as they get transferred
+        let abs: Array = live.iter().map(|member| member.ab.slice(0)).collect();
+        let run_id = self.begin_run(&pth);
+        let result = pth.exec_batch(&abs).await;
+        self.end_run(&pth, &run_id);
+        let is_dead = self.settle_thread(&pth, false).await;
+
+        for ((i, member), attempts) in live.into_iter().enumerate().zip(attempts) {
+            let (result, ms) = match result {
+                Ok(ref arr) => decode_triple(&arr.unchecked_ref::<Array>().get(i as u32)),
+                Err(ref jsv) => (Err(jsv.clone()), 0.0),
+            };
+            self.stats.record_run(&thread_id, ms, result.is_ok());
+            member.job.set_sent(false);
+
+            if let Err(ref err) = result {
+                if let Some(ms) = self.retry_delay_ms(&member.job, attempts, err, is_dead) {
+                    debug_ln!("retrying batched job {} (attempt {}) in {}ms", member.job.id(), attempts + 1, ms);
+                    member.job.set_status(JobStatus::Pending);
+                    let inner = self.clone();
+                    spawn_local(async move {
+                        if ms > 0 {
+                            sleep(ms).await;
+                        }
+                        inner.resolver.enter_waiting();
+                        let result = inner.execute_ab(member.ab, &member.job).await;
+                        (member.cb)(result);
+                    });
+                    continue;
+                }
+            }
+
+            member.job.set_status(JobStatus::Done);
+            self.stats.record_outcome(Some(result.is_ok()));
+            (member.cb)(result);
+        }
+    }
+}
@@ -0,0 +1,296 @@
+use wasm_mt::{MtClosure, MtAsyncClosure, clos_to_ab, aclos_to_ab, call_main, in_worker, run_ab};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{future_to_promise, JsFuture};
+use js_sys::{ArrayBuffer, Object, Promise, Reflect};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::cell::RefCell;
+use super::{ResultJJ, ThreadPool, ThreadPoolInner, JobHandle};
+
+type InlineFn = Box<dyn FnOnce() -> Pin<Box<dyn Future<Output = ResultJJ>>>>;
+
+// A job that can run either on a pool thread or inline on the current thread;
+// the closure is serialized only when taken the former way
+struct Task {
+    is_shared: bool,
+    to_ab: Box<dyn FnOnce() -> ArrayBuffer>,
+    inline: InlineFn,
+}
+
+impl Task {
+    fn new<F>(clos: F) -> Self where F: MtClosure {
+        let clos = Rc::new(RefCell::new(Some(clos)));
+        let clos_ab = clos.clone();
+        Self {
+            is_shared: false,
+            to_ab: Box::new(move || clos_to_ab(clos_ab.borrow_mut().take().unwrap())),
+            inline: Box::new(move || {
+                let clos = clos.borrow_mut().take().unwrap();
+                Box::pin(async move { clos() })
+            }),
+        }
+    }
+
+    fn new_async<F, T>(aclos: F) -> Self where F: MtAsyncClosure<T> {
+        let aclos = Rc::new(RefCell::new(Some(aclos)));
+        let aclos_ab = aclos.clone();
+        Self {
+            is_shared: true,
+            to_ab: Box::new(move || aclos_to_ab(aclos_ab.borrow_mut().take().unwrap())),
+            inline: Box::new(move || {
+                let aclos = aclos.borrow_mut().take().unwrap();
+                Box::pin(async move { aclos().await })
+            }),
+        }
+    }
+}
+
+// Where nested tasks go: directly to the pool given one, from within a job
+// back to the pool owning its thread, or else inline on the current thread
+enum Owner {
+    Pool(Rc<ThreadPoolInner>),
+    Worker,
+    Local,
+}
+
+impl Owner {
+    fn current() -> Self {
+        if in_worker() { Owner::Worker } else { Owner::Local }
+    }
+
+    // Only tasks submitted to the pool directly come with a handle to cancel
+    // them; the others come with a fallback to run if the task is declined.
+    fn submit(&self, task: Task) -> (Promise, Option<JobHandle>, Option<InlineFn>) {
+        match self {
+            Owner::Pool(pool_inner) => {
+                let (pool_inner, ab) = (pool_inner.clone(), (task.to_ab)());
+                let state = pool_inner.new_job(task.is_shared);
+                let handle = JobHandle(state.clone());
+                (future_to_promise(async move {
+                    pool_inner.execute_ab(ab, &state).await
+                }), Some(handle), None)
+            },
+            Owner::Worker => {
+                // Not transferred, so that the fallback can still run it
+                let (ab, is_shared) = ((task.to_ab)(), task.is_shared);
+                let payload = encode_fork_msg(&ab, is_shared);
+                let fallback: InlineFn = Box::new(move || Box::pin(async move { run_ab(&ab).await }));
+                (future_to_promise(async move {
+                    call_main(&payload, None).await
+                }), None, Some(fallback))
+            },
+            Owner::Local => (Promise::resolve(&JsValue::NULL), None, Some(task.inline)),
+        }
+    }
+
+    // A task the pool declined to take, having no free thread, is run inline
+    async fn wait(&self, promise: Promise, fallback: Option<InlineFn>) -> ResultJJ {
+        let result = JsFuture::from(promise).await;
+        match fallback {
+            None => result,
+            // Anything but a result, e.g. from a handler other than the
+            // pool's, counts as declined
+            Some(fallback) => match result.ok().as_ref().and_then(decode_fork_result) {
+                Some(result) => result,
+                None => fallback().await,
+            },
+        }
+    }
+
+    async fn join(&self, a: Task, b: Task) -> (ResultJJ, ResultJJ) {
+        let (promise_b, _, fallback_b) = self.submit(b);
+        let result_a = match self {
+            Owner::Pool(_) => {
+                let (promise_a, _, fallback_a) = self.submit(a);
+                self.wait(promise_a, fallback_a).await
+            },
+            _ => (a.inline)().await,
+        };
+        (result_a, self.wait(promise_b, fallback_b).await)
+    }
+}
+
+// Tasks are tagged, so that other payloads of `call_main()` can be told apart
+
+fn encode_fork_msg(ab: &ArrayBuffer, is_shared: bool) -> Object {
+    let msg = Object::new();
+    Reflect::set(msg.as_ref(), &JsValue::from("forkJob"), ab).unwrap();
+    Reflect::set(msg.as_ref(), &JsValue::from("isShared"), &JsValue::from(is_shared)).unwrap();
+    msg
+}
+
+fn decode_fork_msg(msg: &JsValue) -> Option<(ArrayBuffer, bool)> {
+    if !msg.is_object() {
+        return None;
+    }
+    let ab = Reflect::get(msg, &JsValue::from("forkJob")).ok()?.dyn_into::<ArrayBuffer>().ok()?;
+    let is_shared = Reflect::get(msg, &JsValue::from("isShared")).ok()?.as_bool()?;
+    Some((ab, is_shared))
+}
+
+fn encode_fork_result(result: ResultJJ) -> Object {
+    let (jsv, is_ok) = match result {
+        Ok(jsv) => (jsv, true),
+        Err(jsv) => (jsv, false),
+    };
+    let msg = Object::new();
+    Reflect::set(msg.as_ref(), &JsValue::from("forkResult"), &jsv).unwrap();
+    Reflect::set(msg.as_ref(), &JsValue::from("isOk"), &JsValue::from(is_ok)).unwrap();
+    msg
+}
+
+fn decode_fork_result(msg: &JsValue) -> Option<ResultJJ> {
+    if !msg.is_object() || !Reflect::has(msg, &JsValue::from("forkResult")).unwrap_or(false) {
+        return None;
+    }
+    let jsv = Reflect::get(msg, &JsValue::from("forkResult")).ok()?;
+    let is_ok = Reflect::get(msg, &JsValue::from("isOk")).ok()?.as_bool()?;
+    Some(if is_ok { Ok(jsv) } else { Err(jsv) })
+}
+
+// Serves `call_main()` made by `Owner::Worker`; resolves to `null` if declined,
+// and rejects payloads other than tasks
+pub(crate) fn handle_call(pool_inner: Rc<ThreadPoolInner>, payload: JsValue) -> Promise {
+    let (ab, is_shared) = match decode_fork_msg(&payload) {
+        Some(task) => task,
+        None => return Promise::reject(&JsValue::from("ThreadPool: unexpected call payload")),
+    };
+
+    // Queueing is left out as the caller's thread would be kept waiting for
+    // it; with all threads blocked like this, the pool would deadlock.
+    let pth = match pool_inner.reserve_thread(is_shared, None) {
+        Some(pth) => pth,
+        None => return Promise::resolve(&JsValue::NULL),
+    };
+
+    future_to_promise(async move {
+        let state = pool_inner.new_job(is_shared);
+        state.set_reserved(pth);
+        Ok(encode_fork_result(pool_inner.execute_ab(ab, &state).await).into())
+    })
+}
+
+struct ScopeInner {
+    owner: Owner,
+    tasks: RefCell<Vec<(Promise, Option<InlineFn>)>>,
+    handles: RefCell<Vec<JobHandle>>,
+    first_error: RefCell<Option<JsValue>>,
+}
+
+impl ScopeInner {
+    fn fail(&self, err: &JsValue) {
+        if self.first_error.borrow().is_some() {
+            return; // e.g. a sibling failing for being canceled
+        }
+        self.first_error.replace(Some(err.clone()));
+
+        let handles = self.handles.borrow().clone();
+        handles.iter().for_each(|handle| handle.cancel());
+    }
+}
+
+#[derive(Clone)]
+pub struct Scope(Rc<ScopeInner>);
+
+impl Scope {
+    fn new(owner: Owner) -> Self {
+        Self(Rc::new(ScopeInner {
+            owner,
+            tasks: RefCell::new(Vec::new()),
+            handles: RefCell::new(Vec::new()),
+            first_error: RefCell::new(None),
+        }))
+    }
+
+    pub fn spawn<F>(&self, job: F) where F: MtClosure {
+        self.push(Task::new(job));
+    }
+
+    pub fn spawn_async<F, T>(&self, job: F) where F: MtAsyncClosure<T> {
+        self.push(Task::new_async(job));
+    }
+
+    fn push(&self, task: Task) {
+        let (promise, handle, fallback) = self.0.owner.submit(task);
+        if let Some(handle) = handle {
+            if self.0.first_error.borrow().is_some() {
+                handle.cancel();
+            }
+            self.0.handles.borrow_mut().push(handle);
+        }
+
+        // An error from the pool fails the scope right away, while that of a
+        // task with a fallback only leads to running it inline
+        let promise = if fallback.is_some() { promise } else {
+            let inner = self.0.clone();
+            future_to_promise(async move {
+                let result = JsFuture::from(promise).await;
+                if let Err(ref jsv) = result {
+                    inner.fail(jsv);
+                }
+                result
+            })
+        };
+        self.0.tasks.borrow_mut().push((promise, fallback));
+    }
+
+    async fn run<S, T>(self, f: S) -> Result<Vec<JsValue>, JsValue> where
+    S: FnOnce(Scope) -> T, T: Future<Output = ()> {
+        f(self.clone()).await;
+
+        let tasks = self.0.tasks.replace(Vec::new());
+        let mut outputs = Vec::with_capacity(tasks.len());
+        for (promise, fallback) in tasks {
+            let result = self.0.owner.wait(promise, fallback).await;
+            match result {
+                Ok(jsv) => outputs.push(jsv),
+                Err(ref jsv) => self.0.fail(jsv), // e.g. of a task run inline
+            }
+        }
+
+        match self.0.first_error.replace(None) {
+            Some(err) => Err(err),
+            None => Ok(outputs),
+        }
+    }
+}
+
+// Within a pool job, `b` goes to the pool owning the job's thread if it has a
+// free one; elsewhere, e.g. on the main thread, both run inline in turn.
+pub async fn join<A, B>(a: A, b: B) -> (ResultJJ, ResultJJ) where A: MtClosure, B: MtClosure {
+    Owner::current().join(Task::new(a), Task::new(b)).await
+}
+
+pub async fn join_async<A, TA, B, TB>(a: A, b: B) -> (ResultJJ, ResultJJ) where
+A: MtAsyncClosure<TA>, B: MtAsyncClosure<TB> {
+    Owner::current().join(Task::new_async(a), Task::new_async(b)).await
+}
+
+// Like `ThreadPool::scope()`, except that siblings of a failed job are left
+// running, being out of reach from within a job; outside of jobs, the spawned
+// jobs run inline in turn.
+pub async fn scope<S, T>(f: S) -> Result<Vec<JsValue>, JsValue> where
+S: FnOnce(Scope) -> T, T: Future<Output = ()> {
+    Scope::new(Owner::current()).run(f).await
+}
+
+impl ThreadPool {
+    pub async fn join<A, B>(&self, a: A, b: B) -> (ResultJJ, ResultJJ) where A: MtClosure, B: MtClosure {
+        Owner::Pool(self.0.clone()).join(Task::new(a), Task::new(b)).await
+    }
+
+    pub async fn join_async<A, TA, B, TB>(&self, a: A, b: B) -> (ResultJJ, ResultJJ) where
+    A: MtAsyncClosure<TA>, B: MtAsyncClosure<TB> {
+        Owner::Pool(self.0.clone()).join(Task::new_async(a), Task::new_async(b)).await
+    }
+
+    // Returns once all the jobs spawned by `f` are done, with their outputs in
+    // order.  The first job to fail gets its siblings canceled, and its error
+    // returned; this includes jobs canceled by the pool being dropped.
+    pub async fn scope<S, T>(&self, f: S) -> Result<Vec<JsValue>, JsValue> where
+    S: FnOnce(Scope) -> T, T: Future<Output = ()> {
+        Scope::new(Owner::Pool(self.0.clone())).run(f).await
+    }
+}
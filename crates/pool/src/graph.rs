@@ -0,0 +1,102 @@
+use wasm_mt::{MtClosureArgs, MtAsyncClosureArgs};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{future_to_promise, JsFuture};
+use js_sys::{Array, Promise};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use super::{ResultJJ, ThreadPoolInner};
+
+type NodeFn = Box<dyn FnOnce(Rc<ThreadPoolInner>, Vec<JsValue>) -> Pin<Box<dyn Future<Output = ResultJJ>>>>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NodeId(usize);
+
+impl NodeId {
+    // Index of the node's result in the vector returned by `ThreadPool::run_graph()`
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+struct Node {
+    deps: Vec<NodeId>,
+    run: NodeFn,
+}
+
+// A node's job takes the outputs of its dependencies, in the order given to
+// `add()`, as input.  Dependencies have to be added before their dependents,
+// which keeps any graph acyclic.
+pub struct TaskGraph {
+    nodes: Vec<Node>,
+}
+
+impl TaskGraph {
+    pub fn new() -> Self {
+        Self { nodes: Vec::new() }
+    }
+
+    pub fn add<F>(&mut self, job: F, deps: &[NodeId]) -> NodeId where F: MtClosureArgs {
+        self.add_node(deps, Box::new(move |pool_inner: Rc<ThreadPoolInner>, inputs: Vec<JsValue>| {
+            Box::pin(async move {
+                let state = pool_inner.new_job(false);
+                pool_inner.execute_args(job, inputs, &state).await
+            })
+        }))
+    }
+
+    pub fn add_async<F, T>(&mut self, job: F, deps: &[NodeId]) -> NodeId where F: MtAsyncClosureArgs<T> {
+        self.add_node(deps, Box::new(move |pool_inner: Rc<ThreadPoolInner>, inputs: Vec<JsValue>| {
+            Box::pin(async move {
+                let state = pool_inner.new_job(true);
+                pool_inner.execute_async_args(job, inputs, &state).await
+            })
+        }))
+    }
+
+    fn add_node(&mut self, deps: &[NodeId], run: NodeFn) -> NodeId {
+        assert!(deps.iter().all(|dep| dep.0 < self.nodes.len()), "TaskGraph: unknown dependency");
+        self.nodes.push(Node { deps: deps.to_vec(), run });
+        NodeId(self.nodes.len() - 1)
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    // Every node is dispatched as soon as all of its dependencies are done, so
+    // independent branches run in parallel.  A node whose dependency fails is
+    // not dispatched and fails in turn.
+    pub(crate) async fn run(self, pool_inner: Rc<ThreadPoolInner>) -> Vec<ResultJJ> {
+        let mut promises: Vec<Promise> = Vec::with_capacity(self.nodes.len());
+        for (idx, node) in self.nodes.into_iter().enumerate() {
+            let deps: Array = node.deps.iter().map(|dep| promises[dep.0].clone()).collect();
+            let pool_inner = pool_inner.clone();
+            promises.push(future_to_promise(async move {
+                let inputs = match JsFuture::from(Promise::all(&deps)).await {
+                    Ok(outputs) => outputs.dyn_into::<Array>().unwrap().iter().collect(),
+                    Err(_) => return Err(JsValue::from(
+                        format!("TaskGraph: node[{}] skipped; a dependency failed", idx))),
+                };
+                (node.run)(pool_inner, inputs).await
+            }));
+        }
+
+        let mut results = Vec::with_capacity(promises.len());
+        for promise in promises {
+            results.push(JsFuture::from(promise).await);
+        }
+        results
+    }
+}
+
+impl Default for TaskGraph {
+    fn default() -> Self {
+        Self::new()
+    }
+}
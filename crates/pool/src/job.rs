@@ -0,0 +1,181 @@
+use wasm_mt::Thread;
+use wasm_bindgen::prelude::*;
+use js_sys::{Date, Function, Promise};
+use std::rc::{Rc, Weak};
+use std::cell::RefCell;
+use super::ThreadPoolInner;
+use super::retry::RetryPolicy;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum JobStatus {
+    Pending,
+    Running,
+    Done,
+    Canceled,
+}
+
+pub(crate) struct JobState {
+    id: usize,
+    is_shared: bool,
+    status: RefCell<JobStatus>,
+    pool_inner: Weak<ThreadPoolInner>,
+    cancel_res: RefCell<Option<Function>>,
+    cancel_promise: Promise,
+    submitted_at: f64,
+    retry_policy: RefCell<Option<RetryPolicy>>,
+    attempts: RefCell<usize>,
+    last_thread_id: RefCell<Option<String>>,
+    reserved: RefCell<Option<Rc<Thread>>>,
+    is_sent: RefCell<bool>,
+}
+
+impl JobState {
+    pub fn new(id: usize, is_shared: bool, pool_inner: &Rc<ThreadPoolInner>) -> Self {
+        let mut cancel_res = None;
+        let cancel_promise = Promise::new(&mut |res, _rej| cancel_res = Some(res));
+
+        Self {
+            id,
+            is_shared,
+            status: RefCell::new(JobStatus::Pending),
+            pool_inner: Rc::downgrade(pool_inner),
+            cancel_res: RefCell::new(cancel_res),
+            cancel_promise,
+            submitted_at: Date::now(),
+            retry_policy: RefCell::new(None),
+            attempts: RefCell::new(0),
+            last_thread_id: RefCell::new(None),
+            reserved: RefCell::new(None),
+            is_sent: RefCell::new(false),
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn is_shared(&self) -> bool {
+        self.is_shared
+    }
+
+    pub fn status(&self) -> JobStatus {
+        *self.status.borrow()
+    }
+
+    pub fn submitted_at(&self) -> f64 {
+        self.submitted_at
+    }
+
+    pub fn set_status(&self, status: JobStatus) {
+        self.status.replace(status);
+    }
+
+    pub fn retry_policy(&self) -> Option<RetryPolicy> {
+        self.retry_policy.borrow().clone()
+    }
+
+    pub fn set_retry_policy(&self, policy: RetryPolicy) {
+        self.retry_policy.replace(Some(policy));
+    }
+
+    pub fn attempts(&self) -> usize {
+        *self.attempts.borrow()
+    }
+
+    pub fn last_thread_id(&self) -> Option<String> {
+        self.last_thread_id.borrow().clone()
+    }
+
+    // A thread already occupied for the job, taken instead of queueing for one
+    pub fn set_reserved(&self, pth: Rc<Thread>) {
+        self.reserved.replace(Some(pth));
+    }
+
+    pub fn take_reserved(&self) -> Option<Rc<Thread>> {
+        self.reserved.replace(None)
+    }
+
+    // Whether the job is running as a member of a batch already sent, which
+    // can no longer be canceled
+    pub fn set_sent(&self, tf: bool) {
+        self.is_sent.replace(tf);
+    }
+
+    // Record the start of a run on the thread of `thread_id`; returns the attempt count
+    pub fn start_attempt(&self, thread_id: &str) -> usize {
+        self.last_thread_id.replace(Some(thread_id.to_string()));
+        self.set_status(JobStatus::Running);
+        let mut attempts = self.attempts.borrow_mut();
+        *attempts += 1;
+        *attempts
+    }
+
+    pub fn is_canceled(&self) -> bool {
+        self.status() == JobStatus::Canceled
+    }
+
+    // Settles once the job gets canceled; raced against the job's execution
+    pub fn cancel_promise(&self) -> &Promise {
+        &self.cancel_promise
+    }
+
+    pub fn canceled_error(&self) -> JsValue {
+        JsValue::from(format!("ThreadPool: job[{}] canceled", self.id))
+    }
+
+    fn cancel(&self) {
+        match self.status() {
+            JobStatus::Pending => {
+                self.set_status(JobStatus::Canceled);
+                if let Some(pool_inner) = self.pool_inner.upgrade() {
+                    pool_inner.resolver.cancel_job(self.id);
+                }
+            },
+            JobStatus::Running if *self.is_sent.borrow() => return,
+            JobStatus::Running => self.set_status(JobStatus::Canceled),
+            JobStatus::Done | JobStatus::Canceled => return,
+        }
+
+        if let Some(res) = self.cancel_res.replace(None) {
+            res.call0(&JsValue::NULL).unwrap();
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct JobHandle(pub(crate) Rc<JobState>);
+
+impl JobHandle {
+    pub fn id(&self) -> usize {
+        self.0.id()
+    }
+
+    pub fn status(&self) -> JobStatus {
+        self.0.status()
+    }
+
+    pub fn is_canceled(&self) -> bool {
+        self.0.is_canceled()
+    }
+
+    // The number of runs so far, including retries; results, whether ok or
+    // not, come as those of the last run
+    pub fn attempts(&self) -> usize {
+        self.0.attempts()
+    }
+
+    // Overrides the pool-wide retry policy for this job
+    pub fn with_retry(self, policy: RetryPolicy) -> Self {
+        self.0.set_retry_policy(policy);
+        self
+    }
+
+    // A pending job is removed from the queue without being dispatched, while
+    // one sent to a worker as part of a batch runs to completion.  A
+    // running async job is aborted by its worker where it is awaiting; for one
+    // that cannot be, e.g. of async JS or a sync job still running after the
+    // pool's cancel grace period, the thread is terminated and respawned.
+    pub fn cancel(&self) {
+        self.0.cancel();
+    }
+}
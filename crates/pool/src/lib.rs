@@ -83,105 +83,778 @@
 #![feature(async_closure)]
 
 pub use wasm_mt;
-use wasm_mt::{debug_ln, WasmMt, Thread, MtClosure, MtAsyncClosure};
-use js_sys::ArrayBuffer;
+use wasm_mt::{debug_ln, WasmMt, InitError, Thread, MtClosure, MtAsyncClosure, MtClosureArgs, MtAsyncClosureArgs,
+    clos_to_ab, aclos_to_ab, clos_args_to_ab, aclos_args_to_ab};
+use wasm_mt::utils::{sleep, Counter};
+use js_sys::{Array, ArrayBuffer, Date, Promise};
 
 pub mod prelude;
 mod resolver;
 use resolver::Resolver;
+mod job;
+use job::JobState;
+pub use job::{JobHandle, JobStatus};
+mod retry;
+pub use retry::{RetryPolicy, RetryPredicate};
+mod stats;
+use stats::Stats;
+pub use stats::{PoolStats, ThreadStats, Percentiles};
+mod graph;
+pub use graph::{TaskGraph, NodeId};
+mod par;
+pub use par::{ParItem, ParFn, ParReduceFn};
+mod fork;
+pub use fork::{Scope, join, join_async, scope};
+mod race;
+pub use race::RaceJob;
+mod batch;
 
 use wasm_bindgen::prelude::*;
-use wasm_bindgen_futures::spawn_local;
-use std::rc::Rc;
+use wasm_bindgen_futures::{future_to_promise, spawn_local, JsFuture};
+use std::rc::{Rc, Weak};
 use std::cell::RefCell;
+use std::collections::HashMap;
 
 type ResultJJ = Result<JsValue, JsValue>;
 
 pub trait PoolCallback = FnOnce(ResultJJ,) -> () + 'static;
+pub trait PoolEventListener = Fn(&PoolEvent,) -> () + 'static;
+
+#[derive(Clone, Debug)]
+pub enum PoolEvent {
+    WorkerReplaced { thread_id: String, reason: String },
+}
+
+// What to do with a job whose worker dies while running it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeadWorkerPolicy {
+    Fail,
+    Requeue, // at most once, so that a job crashing every worker still fails
+}
+
+// Given the thread and an id for the run, by which it can be canceled
+type RunFn = Box<dyn Fn(Rc<Thread>, String) -> Promise>;
+
+fn is_dead(pth: &Thread) -> bool {
+    pth.is_terminated() || pth.has_error()
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Autoscale {
+    min: usize,
+    max: usize,
+    idle_timeout_ms: u32,
+}
+
+// Threads have their memory size sampled for recycling after their first run,
+// then every this many runs or milliseconds, as it takes a round trip to the worker
+const MEMORY_SAMPLE_RUNS: usize = 8;
+const MEMORY_SAMPLE_MS: f64 = 1_000.0;
+
+#[derive(Clone, Copy, Debug)]
+struct Recycle {
+    max_jobs: Option<usize>,
+    max_memory_bytes: Option<f64>,
+}
 
 struct ThreadPoolInner {
-    size: usize,
+    size: RefCell<usize>,
     mt: WasmMt,
-    threads: RefCell<Vec<Thread>>,
+    threads: RefCell<Vec<Rc<Thread>>>,
     resolver: Resolver,
+    next_id: Counter,
+    num_spawning: RefCell<usize>,
+    autoscale: RefCell<Option<Autoscale>>,
+    is_reaper_running: RefCell<bool>,
+    is_dropped: RefCell<bool>,
+    next_job_id: Counter,
+    cancel_grace_ms: RefCell<u32>,
+    dead_worker_policy: RefCell<DeadWorkerPolicy>,
+    event_listener: RefCell<Option<Rc<dyn Fn(&PoolEvent)>>>,
+    stats: Stats,
+    retry_policy: RefCell<Option<RetryPolicy>>,
+    weak_self: RefCell<Weak<ThreadPoolInner>>,
+    batch_size: RefCell<Option<usize>>,
+    batch: RefCell<Vec<batch::Member>>,
+    worker_init: RefCell<Option<Rc<dyn Fn(Rc<Thread>) -> Promise>>>,
+    standby: RefCell<Option<usize>>,
+    idle_timeout_ms: RefCell<Option<u32>>,
+    recycle: RefCell<Option<Recycle>>,
+    runs: RefCell<HashMap<String, Vec<String>>>,
+    // Number of runs and time as of the last memory sample, by thread id
+    memory_samples: RefCell<HashMap<String, (usize, f64)>>,
 }
 
 impl ThreadPoolInner {
     fn new(size: usize, pkg_js_uri: &str) -> Self {
-        assert!(size > 0);
-        Self {
-            size,
-            mt: WasmMt::new(pkg_js_uri),
-            threads: RefCell::new(Vec::with_capacity(size)),
-            resolver: Resolver::new(),
-        }
+        Self::new_with_mt(size, WasmMt::new(pkg_js_uri))
     }
 
     fn new_with_arraybuffers(size: usize, ab_js: ArrayBuffer, ab_wasm: ArrayBuffer) -> Self {
+        Self::new_with_mt(size, WasmMt::new_with_arraybuffers(ab_js, ab_wasm))
+    }
+
+    fn new_with_mt(size: usize, mt: WasmMt) -> Self {
         assert!(size > 0);
         Self {
-            size,
-            mt: WasmMt::new_with_arraybuffers(ab_js, ab_wasm),
+            size: RefCell::new(size),
+            mt,
             threads: RefCell::new(Vec::with_capacity(size)),
             resolver: Resolver::new(),
+            next_id: Counter::new(),
+            num_spawning: RefCell::new(0),
+            autoscale: RefCell::new(None),
+            is_reaper_running: RefCell::new(false),
+            is_dropped: RefCell::new(false),
+            next_job_id: Counter::new(),
+            cancel_grace_ms: RefCell::new(DEFAULT_CANCEL_GRACE_MS),
+            dead_worker_policy: RefCell::new(DeadWorkerPolicy::Fail),
+            event_listener: RefCell::new(None),
+            stats: Stats::new(),
+            retry_policy: RefCell::new(None),
+            weak_self: RefCell::new(Weak::new()),
+            batch_size: RefCell::new(None),
+            batch: RefCell::new(Vec::new()),
+            worker_init: RefCell::new(None),
+            standby: RefCell::new(None),
+            idle_timeout_ms: RefCell::new(None),
+            recycle: RefCell::new(None),
+            runs: RefCell::new(HashMap::new()),
+            memory_samples: RefCell::new(HashMap::new()),
         }
     }
 
-    async fn init(&self) -> Result<(), JsValue> {
-        let mut threads = self.threads.borrow_mut();
+    fn into_rc(self) -> Rc<Self> {
+        let inner = Rc::new(self);
+        inner.weak_self.replace(Rc::downgrade(&inner));
+        inner
+    }
 
+    async fn init(&self) -> Result<(), JsValue> {
         self.mt.init().await?;
-        for id in 0..self.size {
-            let pth = self.mt.thread();
-            pth.set_id(&id.to_string());
-            threads.push(pth);
+
+        let size = self.clamp_size(*self.size.borrow());
+        self.size.replace(size);
+        match *self.standby.borrow() {
+            Some(standby) => self.spawn_threads(standby.min(size)).await,
+            None => self.spawn_threads(size).await,
+        }
+    }
+
+    fn clamp_size(&self, size: usize) -> usize {
+        match *self.autoscale.borrow() {
+            Some(Autoscale { min, max, .. }) => size.max(min).min(max),
+            None => size,
         }
+    }
 
-        for pth in threads.iter() {
-            pth.init().await?;
+    fn max_size(&self) -> usize {
+        match *self.autoscale.borrow() {
+            Some(Autoscale { max, .. }) => max,
+            None => *self.size.borrow(),
         }
+    }
 
-        Ok(())
+    fn count_threads(&self) -> usize {
+        self.threads.borrow().len() + *self.num_spawning.borrow()
     }
 
-    async fn execute<F>(&self, clos: F) -> ResultJJ where F: MtClosure {
-        let threads = self.threads.borrow();
-        let pth = self.resolver.resolve_runnable(&threads).await?;
+    async fn spawn_threads(&self, num: usize) -> Result<(), JsValue> {
+        *self.num_spawning.borrow_mut() += num;
 
-        let result = pth.exec(clos).await;
-        debug_ln!("pth {} done with result: {:?}", pth.get_id().unwrap(), result);
-        self.resolver.notify_job_complete(pth);
-        result
+        let ths: Vec<_> = (0..num).map(|_| {
+            let pth = Rc::new(self.mt.thread());
+            pth.set_id(&(self.next_id.inc() - 1).to_string());
+            pth
+        }).collect();
+
+        // Bootstrap all at once, each followed by the worker init hook if any
+        let worker_init = self.worker_init.borrow().clone();
+        let promises: Vec<_> = ths.iter().map(|pth| {
+            let (pth, worker_init) = (pth.clone(), worker_init.clone());
+            future_to_promise(async move {
+                pth.init().await?;
+                if let Some(run) = worker_init {
+                    JsFuture::from(run(pth)).await?;
+                }
+                Ok(JsValue::UNDEFINED)
+            })
+        }).collect();
+
+        // Failures are listed by thread id, each with its error, e.g. that of
+        // the worker init hook
+        let mut failed = Vec::new();
+        for (pth, promise) in ths.into_iter().zip(promises) {
+            let result = JsFuture::from(promise).await;
+            *self.num_spawning.borrow_mut() -= 1;
+
+            if let Err(jsv) = result {
+                debug_ln!("failed to spawn pth {}: {:?}", pth.get_id().unwrap(), jsv);
+                failed.push((pth.get_id().unwrap().parse().unwrap(), jsv));
+                pth.terminate();
+                continue;
+            }
+            if *self.is_dropped.borrow() {
+                pth.terminate();
+                continue;
+            }
+
+            let weak = self.weak_self.borrow().clone();
+            pth.set_call_handler(move |payload| match weak.upgrade() {
+                Some(pool_inner) => fork::handle_call(pool_inner, payload),
+                None => Promise::reject(&JsValue::from("ThreadPool: dropped")),
+            });
+            // Replaced right away if idle, else once done with its runs
+            let weak = self.weak_self.borrow().clone();
+            pth.set_error_handler(move || if let Some(pool_inner) = weak.upgrade() {
+                spawn_local(async move { pool_inner.check_health().await });
+            });
+
+            self.threads.borrow_mut().push(pth.clone());
+            debug_ln!("spawned pth {}; {} threads in pool", pth.get_id().unwrap(), self.threads.borrow().len());
+            self.resolver.notify_thread_ready(&pth);
+        }
+
+        if failed.is_empty() {
+            Ok(())
+        } else {
+            Err(InitError { failed }.into())
+        }
+    }
+
+    // Down to 0 only under autoscaling, which spawns threads again on demand
+    async fn resize(&self, size: usize) -> Result<(), JsValue> {
+        let size = self.clamp_size(size);
+        if size == 0 && self.autoscale.borrow().is_none() {
+            return Err(JsValue::from("ThreadPool: size must be positive without autoscaling"));
+        }
+        self.size.replace(size);
+
+        let num = self.count_threads();
+        if size > num {
+            if self.standby.borrow().is_some() {
+                self.top_up_standby();
+                return Ok(());
+            }
+            self.spawn_threads(size - num).await
+        } else {
+            self.terminate_idle_threads(num - size, 0.0);
+            Ok(())
+        }
     }
 
-    async fn execute_async<F, T>(&self, aclos: F) -> ResultJJ where F: MtAsyncClosure<T> {
-        let threads = self.threads.borrow();
-        let pth = self.resolver.resolve_runnable(&threads).await?;
+    // In lazy mode, spawn threads in the background until `standby` of them
+    // are idle, within the pool size
+    fn top_up_standby(&self) {
+        if self.standby.borrow().is_none() {
+            return;
+        }
 
-        let result = pth.exec_async(aclos).await;
-        debug_ln!("pth {} done with result: {:?}", pth.get_id().unwrap(), result);
-        self.resolver.notify_job_complete(pth);
-        result
+        let weak = self.weak_self.borrow().clone();
+        spawn_local(async move {
+            let inner = match weak.upgrade() {
+                Some(inner) => inner,
+                None => return,
+            };
+            let standby = inner.standby.borrow().unwrap_or(0);
+            let num_idle = inner.threads.borrow().iter().filter(|pth| !pth.get_busy()).count() +
+                *inner.num_spawning.borrow();
+            let num = standby.saturating_sub(num_idle)
+                .min(inner.max_size().saturating_sub(inner.count_threads()));
+            if num > 0 {
+                if let Err(ref jsv) = inner.spawn_threads(num).await {
+                    debug_ln!("top_up_standby(): failed to spawn: {:?}", jsv);
+                }
+            }
+        });
+    }
+
+    // Terminate up to `num` threads that have been idle for at least `idle_ms`.
+    // Busy threads in excess are retired later by `release_thread()`.
+    fn terminate_idle_threads(&self, num: usize, idle_ms: f64) {
+        let mut excess = num;
+        self.threads.borrow_mut().retain(|pth| {
+            if excess > 0 && pth.terminate_if_idle(idle_ms) {
+                debug_ln!("terminated idle pth {}", pth.get_id().unwrap());
+                excess -= 1;
+                self.discard_thread(pth);
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    // The number of threads to keep regardless, and the idle timeout for the rest
+    fn idle_policy(&self) -> Option<(usize, u32)> {
+        match (*self.autoscale.borrow(), *self.idle_timeout_ms.borrow()) {
+            (Some(Autoscale { min, idle_timeout_ms, .. }), _) => Some((min, idle_timeout_ms)),
+            (None, Some(ms)) => Some((self.standby.borrow().unwrap_or(0), ms)),
+            (None, None) => None,
+        }
+    }
+
+    fn reap_idle_threads(&self) {
+        if let Some((min, idle_timeout_ms)) = self.idle_policy() {
+            let excess = self.count_threads().saturating_sub(min);
+            self.terminate_idle_threads(excess, idle_timeout_ms as f64);
+        }
+    }
+
+    fn run_reaper(inner: &Rc<Self>) {
+        if inner.is_reaper_running.replace(true) {
+            return;
+        }
+
+        let weak = Rc::downgrade(inner);
+        spawn_local(async move {
+            while let Some(ms) = Self::reaper_interval(&weak) {
+                sleep(ms).await;
+                match weak.upgrade() {
+                    Some(inner) => inner.reap_idle_threads(),
+                    None => break,
+                }
+            }
+            debug_ln!("reaper: exiting");
+        });
     }
 
-    async fn execute_js(&self, js: &str, is_async: bool) -> ResultJJ {
-        let threads = self.threads.borrow();
-        let pth = self.resolver.resolve_runnable(&threads).await?;
+    fn reaper_interval(weak: &Weak<Self>) -> Option<u32> {
+        let inner = weak.upgrade()?;
+        let ms = match (*inner.is_dropped.borrow(), inner.idle_policy()) {
+            (false, Some((_, idle_timeout_ms))) => Some((idle_timeout_ms / 2).max(100)),
+            _ => None,
+        };
+        if ms.is_none() {
+            inner.is_reaper_running.replace(false);
+        }
+        ms
+    }
+
+    // Terminate a thread that has been removed from `threads`
+    fn discard_thread(&self, pth: &Thread) {
+        pth.terminate();
+        self.runs.borrow_mut().remove(pth.get_id().unwrap().as_str());
+        self.memory_samples.borrow_mut().remove(pth.get_id().unwrap().as_str());
+        self.resolver.forget_thread(pth);
+        self.stats.forget_thread(&pth.get_id().unwrap());
+    }
 
-        let result = if is_async {
-            pth.exec_js_async(js).await
+    // Async jobs are shared, i.e. they can run concurrently on a thread
+    fn new_job(self: &Rc<Self>, is_shared: bool) -> Rc<JobState> {
+        self.resolver.enter_waiting();
+        Rc::new(JobState::new(self.next_job_id.inc() - 1, is_shared, self))
+    }
+
+    async fn acquire_thread(&self, job: &JobState) -> Result<Rc<Thread>, JsValue> {
+        let result = self.acquire_thread_inner(job).await;
+        self.resolver.leave_waiting();
+
+        match result {
+            Ok(pth) if job.is_canceled() => {
+                // Canceled while the thread was being handed over
+                self.release_thread(&pth, job.is_shared());
+                Err(job.canceled_error())
+            },
+            _ => result,
+        }
+    }
+
+    async fn acquire_thread_inner(&self, job: &JobState) -> Result<Rc<Thread>, JsValue> {
+        if let Some(pth) = job.take_reserved() {
+            return Ok(pth);
+        }
+        if *self.is_dropped.borrow() {
+            return Err(JsValue::from("ThreadPool: already dropped"));
+        }
+        if job.is_canceled() {
+            return Err(job.canceled_error());
+        }
+
+        self.check_health().await;
+
+        let is_growable = self.autoscale.borrow().is_some() || self.standby.borrow().is_some() ||
+            self.idle_timeout_ms.borrow().is_some();
+        let is_saturated = !self.resolver.has_runnable(&self.threads, job.is_shared());
+        if is_saturated && is_growable && self.count_threads() < self.max_size() {
+            // The queue is backing up; grow the pool by one, or else leave the
+            // job queued for the threads there are
+            if let Err(ref jsv) = self.spawn_threads(1).await {
+                debug_ln!("acquire_thread_inner(): failed to spawn: {:?}", jsv);
+            }
+        }
+
+        let avoid = job.last_thread_id();
+        let pth = self.resolver.resolve_runnable(&self.threads, job.id(), job.is_shared(), avoid.as_deref()).await?;
+        self.top_up_standby();
+        Ok(pth)
+    }
+
+    // Occupy a thread with room for a job right away, if any, so that no other
+    // job can take it before the job is submitted with `JobState::set_reserved()`
+    fn reserve_thread(&self, is_shared: bool, avoid: Option<&str>) -> Option<Rc<Thread>> {
+        if *self.is_dropped.borrow() {
+            return None;
+        }
+        self.resolver.try_resolve_runnable(&self.threads, is_shared, avoid)
+    }
+
+    // Like `reserve_thread()`, only for an idle thread other than `avoid`
+    fn reserve_idle_thread(&self, is_shared: bool, avoid: &str) -> Option<Rc<Thread>> {
+        if *self.is_dropped.borrow() {
+            return None;
+        }
+        self.resolver.try_resolve_idle(&self.threads, is_shared, avoid)
+    }
+
+    fn is_queue_full(&self) -> bool {
+        self.resolver.is_full(&self.threads)
+    }
+
+    fn check_queue(&self) -> Result<(), JsValue> {
+        if self.is_queue_full() {
+            Err(JsValue::from("ThreadPool: queue is full"))
         } else {
-            pth.exec_js(js).await
+            Ok(())
+        }
+    }
+
+    fn release_thread(&self, pth: &Rc<Thread>, is_shared: bool) {
+        let is_idle = self.resolver.vacate(pth, is_shared);
+
+        let mut threads = self.threads.borrow_mut();
+        let pos = match threads.iter().position(|th| Rc::ptr_eq(th, pth)) {
+            Some(pos) => pos,
+            None => return, // already removed from the pool
         };
-        debug_ln!("pth {} done with result: {:?}", pth.get_id().unwrap(), result);
-        self.resolver.notify_job_complete(pth);
+
+        if threads.len() + *self.num_spawning.borrow() > self.max_size() {
+            // In excess; retire the thread once its jobs in flight are drained
+            if is_idle {
+                debug_ln!("retiring pth {}", pth.get_id().unwrap());
+                threads.remove(pos);
+                drop(threads);
+                self.discard_thread(pth);
+            }
+        } else {
+            drop(threads);
+            self.resolver.notify_thread_ready(pth);
+        }
+    }
+
+    // Jobs are kept as `ArrayBuffer`s, copied per run, so that they can be
+    // re-run on another thread
+    async fn execute<F>(&self, clos: F, job: &JobState) -> ResultJJ where F: MtClosure {
+        self.execute_ab(clos_to_ab(clos), job).await
+    }
+
+    async fn execute_async<F, T>(&self, aclos: F, job: &JobState) -> ResultJJ where F: MtAsyncClosure<T> {
+        self.execute_ab(aclos_to_ab(aclos), job).await
+    }
+
+    async fn execute_args<F>(&self, clos: F, args: Vec<JsValue>, job: &JobState) -> ResultJJ where F: MtClosureArgs {
+        self.execute_ab_with_args(clos_args_to_ab(clos), args, job).await
+    }
+
+    async fn execute_async_args<F, T>(&self, aclos: F, args: Vec<JsValue>, job: &JobState) -> ResultJJ where F: MtAsyncClosureArgs<T> {
+        self.execute_ab_with_args(aclos_args_to_ab(aclos), args, job).await
+    }
+
+    async fn execute_ab_with_args(&self, ab: ArrayBuffer, args: Vec<JsValue>, job: &JobState) -> ResultJJ {
+        let args: Array = args.into_iter().collect();
+        self.execute_with(job, Box::new(move |th: Rc<Thread>, run_id: String| {
+            let (ab, args) = (ab.clone(), args.clone());
+            future_to_promise(async move { th.exec_ab_with_args_as(&run_id, &ab, &args).await })
+        })).await
+    }
+
+    async fn execute_ab(&self, ab: ArrayBuffer, job: &JobState) -> ResultJJ {
+        self.execute_with(job, Box::new(move |th: Rc<Thread>, run_id: String| {
+            let ab = ab.clone();
+            future_to_promise(async move { th.exec_ab_as(&run_id, &ab).await })
+        })).await
+    }
+
+    async fn execute_js(&self, js: String, is_async: bool, job: &JobState) -> ResultJJ {
+        self.execute_with(job, Box::new(move |th: Rc<Thread>, run_id: String| {
+            let js = js.clone();
+            future_to_promise(async move {
+                if is_async {
+                    th.exec_js_async_as(&run_id, &js).await
+                } else {
+                    th.exec_js_as(&run_id, &js).await
+                }
+            })
+        })).await
+    }
+
+    async fn execute_with(&self, job: &JobState, run: RunFn) -> ResultJJ {
+        let result = self.execute_with_inner(job, run).await;
+        self.stats.record_outcome(if job.is_canceled() { None } else { Some(result.is_ok()) });
+        result
+    }
+
+    async fn execute_with_inner(&self, job: &JobState, run: RunFn) -> ResultJJ {
+        let mut queued_at = job.submitted_at();
+        loop {
+            let pth = self.acquire_thread(job).await?;
+            let attempts = job.start_attempt(&pth.get_id().unwrap());
+
+            let started_at = Date::now();
+            self.stats.record_wait(started_at - queued_at);
+
+            let run_id = self.begin_run(&pth);
+            let result = race(&run(pth.clone(), run_id.clone()), job.cancel_promise()).await;
+            self.stats.record_run(&pth.get_id().unwrap(), Date::now() - started_at,
+                result.is_ok() && !job.is_canceled());
+
+            if job.is_canceled() {
+                debug_ln!("pth {} canceled job {}", pth.get_id().unwrap(), job.id());
+                self.recover_thread(pth, &run_id, job.is_shared()).await;
+                return Err(job.canceled_error());
+            }
+            self.end_run(&pth, &run_id);
+
+            let is_dead = self.settle_thread(&pth, job.is_shared()).await;
+            if is_dead {
+                debug_ln!("pth {} died running job {}", pth.get_id().unwrap(), job.id());
+            }
+
+            if let Err(ref err) = result {
+                if let Some(ms) = self.retry_delay_ms(job, attempts, err, is_dead) {
+                    debug_ln!("retrying job {} (attempt {}) in {}ms", job.id(), attempts + 1, ms);
+                    job.set_status(JobStatus::Pending);
+                    if ms > 0 {
+                        sleep(ms).await;
+                    }
+                    self.resolver.enter_waiting();
+                    queued_at = Date::now();
+                    continue;
+                }
+            }
+
+            job.set_status(JobStatus::Done);
+            debug_ln!("pth {} done with result: {:?}", pth.get_id().unwrap(), result);
+            return result;
+        }
+    }
+
+    // Run on the given thread once, with no retries
+    async fn execute_on(&self, pth: Rc<Thread>, is_shared: bool, run: RunFn) -> ResultJJ {
+        let job_id = self.next_job_id.inc() - 1;
+        self.resolver.resolve_thread(&pth, job_id, is_shared).await?;
+
+        let started_at = Date::now();
+        let run_id = self.begin_run(&pth);
+        let result = JsFuture::from(run(pth.clone(), run_id.clone())).await;
+        self.end_run(&pth, &run_id);
+        self.stats.record_run(&pth.get_id().unwrap(), Date::now() - started_at, result.is_ok());
+        self.stats.record_outcome(Some(result.is_ok()));
+
+        self.settle_thread(&pth, is_shared).await;
         result
     }
 
+    // Release a thread done with a run, or replace it if its worker has died
+    // or it is due recycling; returns whether the worker has died
+    async fn settle_thread(&self, pth: &Rc<Thread>, is_shared: bool) -> bool {
+        let is_dead = is_dead(pth);
+        if is_dead {
+            self.replace_thread(pth, "worker died").await;
+        } else if self.is_due_recycling(pth).await {
+            self.replace_thread(pth, "recycled").await;
+        } else {
+            self.release_thread(pth, is_shared);
+        }
+        is_dead
+    }
+
+    // Whether a thread just done with a run, and with no other jobs in flight,
+    // is to be restarted to reclaim its memory
+    async fn is_due_recycling(&self, pth: &Thread) -> bool {
+        let Recycle { max_jobs, max_memory_bytes } = match *self.recycle.borrow() {
+            Some(recycle) => recycle,
+            None => return false,
+        };
+        if self.resolver.count_in_flight(pth) > 1 {
+            return false;
+        }
+
+        let id = pth.get_id().unwrap();
+        let runs = self.stats.count_runs(&id);
+        if max_jobs.map_or(false, |max| runs >= max) {
+            return true;
+        }
+        let max = match max_memory_bytes {
+            Some(max) if self.is_due_memory_sample(&id, runs) => max,
+            _ => return false,
+        };
+
+        let is_over = match pth.get_memory_bytes().await {
+            Ok(bytes) => bytes > max,
+            Err(ref jsv) => {
+                debug_ln!("is_due_recycling(): failed to get memory size: {:?}", jsv);
+                false
+            },
+        };
+        // The thread could take other jobs during the round trip
+        is_over && self.resolver.count_in_flight(pth) <= 1
+    }
+
+    // Whether to sample the memory size of a thread now; if so, recorded as sampled
+    fn is_due_memory_sample(&self, id: &str, runs: usize) -> bool {
+        let now = Date::now();
+        let mut samples = self.memory_samples.borrow_mut();
+        let is_due = match samples.get(id) {
+            Some(&(last_runs, last_at)) =>
+                runs - last_runs >= MEMORY_SAMPLE_RUNS || now - last_at >= MEMORY_SAMPLE_MS,
+            None => true,
+        };
+        if is_due {
+            samples.insert(id.to_string(), (runs, now));
+        }
+        is_due
+    }
+
+    async fn broadcast(self: &Rc<Self>, is_shared: bool, ab: ArrayBuffer) -> Vec<ResultJJ> {
+        let threads: Vec<_> = self.threads.borrow().iter().cloned().collect();
+        let promises: Vec<_> = threads.into_iter().map(|pth| {
+            let (inner, ab) = (self.clone(), ab.clone());
+            future_to_promise(async move {
+                inner.execute_on(pth, is_shared, Box::new(move |th: Rc<Thread>, run_id: String| {
+                    let ab = ab.clone();
+                    future_to_promise(async move { th.exec_ab_as(&run_id, &ab).await })
+                })).await
+            })
+        }).collect();
+
+        let mut results = Vec::with_capacity(promises.len());
+        for promise in promises {
+            results.push(JsFuture::from(promise).await);
+        }
+        results
+    }
+
+    fn retry_delay_ms(&self, job: &JobState, attempts: usize, err: &JsValue, is_dead: bool) -> Option<u32> {
+        if *self.is_dropped.borrow() || job.is_canceled() {
+            return None;
+        }
+
+        let policy = job.retry_policy().or_else(|| self.retry_policy.borrow().clone());
+        match policy {
+            Some(ref policy) if policy.should_retry(attempts, err) => Some(policy.delay_ms(attempts)),
+            _ if is_dead && attempts < 2 &&
+                *self.dead_worker_policy.borrow() == DeadWorkerPolicy::Requeue => Some(0),
+            _ => None,
+        }
+    }
+
+    // Runs in flight on each thread, in the order they were sent to its worker
+    fn begin_run(&self, pth: &Thread) -> String {
+        let run_id = pth.new_run_id();
+        self.runs.borrow_mut().entry(pth.get_id().unwrap().to_string()).or_default()
+            .push(run_id.clone());
+        run_id
+    }
+
+    fn end_run(&self, pth: &Thread, run_id: &str) {
+        if let Some(runs) = self.runs.borrow_mut().get_mut(pth.get_id().unwrap().as_str()) {
+            runs.retain(|id| id != run_id);
+        }
+    }
+
+    // Whether runs sent earlier than `run_id` are still in flight on the
+    // thread, e.g. with prefetching, so that its worker may well be busy
+    // with those rather than stuck in the run of `run_id`
+    fn is_behind_runs(&self, pth: &Thread, run_id: &str) -> bool {
+        self.runs.borrow().get(pth.get_id().unwrap().as_str())
+            .map_or(false, |runs| runs.first().map_or(false, |id| id != run_id))
+    }
+
+    // Put a thread whose job got canceled back to work once the worker has
+    // stopped the run; otherwise the job is taken as uncooperative and the
+    // thread gets replaced.  The grace period restarts while the worker is
+    // still on earlier runs.
+    async fn recover_thread(&self, pth: Rc<Thread>, run_id: &str, is_shared: bool) {
+        let (th, id) = (pth.clone(), run_id.to_string());
+        let cancel = future_to_promise(async move { th.cancel(&id).await.map(JsValue::from) });
+        loop {
+            let ms = *self.cancel_grace_ms.borrow();
+            let timeout = future_to_promise(async move {
+                sleep(ms).await;
+                Err(JsValue::from("timeout"))
+            });
+
+            match race(&cancel, &timeout).await {
+                Ok(jsv) if jsv.as_bool() == Some(true) => {
+                    self.end_run(&pth, run_id);
+                    self.release_thread(&pth, is_shared);
+                },
+                Err(ref jsv) if jsv.as_string().as_deref() == Some("timeout") && !is_dead(&pth) &&
+                    self.is_behind_runs(&pth, run_id) => continue,
+                _ => {
+                    self.end_run(&pth, run_id);
+                    self.replace_thread(&pth, "uncooperative job canceled").await;
+                },
+            }
+            return;
+        }
+    }
+
+    async fn replace_thread(&self, pth: &Rc<Thread>, reason: &str) {
+        let is_member = {
+            let mut threads = self.threads.borrow_mut();
+            let len = threads.len();
+            threads.retain(|th| !Rc::ptr_eq(th, pth));
+            threads.len() < len
+        };
+        if !is_member {
+            pth.terminate();
+            return; // already removed from the pool
+        }
+        self.discard_thread(pth);
+
+        let thread_id = pth.get_id().unwrap().to_string();
+        debug_ln!("replacing pth {}: {}", thread_id, reason);
+        if self.count_threads() < self.max_size() {
+            if let Err(ref jsv) = self.spawn_threads(1).await {
+                debug_ln!("replace_thread(): failed to spawn: {:?}", jsv);
+            }
+        }
+
+        self.emit(&PoolEvent::WorkerReplaced { thread_id, reason: reason.to_string() });
+    }
+
+    // Replace idle threads whose workers have died or got terminated outside the pool
+    async fn check_health(&self) {
+        let dead: Vec<_> = self.threads.borrow().iter()
+            .filter(|pth| !pth.get_busy() && is_dead(pth))
+            .cloned().collect();
+        for pth in dead {
+            self.replace_thread(&pth, "worker died").await;
+        }
+    }
+
+    fn stats(&self) -> PoolStats {
+        let live: Vec<_> = self.threads.borrow().iter()
+            .map(|pth| (pth.get_id().unwrap().to_string(), self.resolver.count_in_flight(pth)))
+            .collect();
+        self.stats.snapshot(&live, self.resolver.count_pending_jobs())
+    }
+
+    fn emit(&self, event: &PoolEvent) {
+        let listener = self.event_listener.borrow().clone();
+        if let Some(listener) = listener {
+            listener(event);
+        }
+    }
+
     fn drop_inner(&self) {
-        debug_ln!("[drop] drop_inner(): terminating {} workers ...", self.size);
+        self.is_dropped.replace(true);
+        let threads: Vec<_> = self.threads.borrow_mut().drain(..).collect();
+        debug_ln!("[drop] drop_inner(): terminating {} workers ...", threads.len());
         self.resolver.cancel_pending_jobs();
-        self.threads.borrow().iter().for_each(|pth| pth.terminate());
+        threads.iter().for_each(|pth| pth.terminate());
     }
 }
 
@@ -206,6 +879,15 @@ macro_rules! pool_exec_js_async {
 }
 
 
+// Assumed when the core count cannot be detected
+const FALLBACK_HARDWARE_CONCURRENCY: usize = 4;
+
+const DEFAULT_CANCEL_GRACE_MS: u32 = 500;
+
+async fn race(a: &Promise, b: &Promise) -> ResultJJ {
+    JsFuture::from(Promise::race(&Array::of2(a, b))).await
+}
+
 pub struct ThreadPool(Rc<ThreadPoolInner>);
 
 impl Drop for ThreadPool {
@@ -217,17 +899,54 @@ impl Drop for ThreadPool {
 
 impl ThreadPool {
     pub fn new(size: usize, pkg_js_uri: &str) -> Self {
-        Self(Rc::new(ThreadPoolInner::new(size, pkg_js_uri)))
+        Self(ThreadPoolInner::new(size, pkg_js_uri).into_rc())
     }
 
     pub fn new_with_arraybuffers(size: usize, ab_js: ArrayBuffer, ab_wasm: ArrayBuffer) -> Self {
-        Self(Rc::new(ThreadPoolInner::new_with_arraybuffers(size, ab_js, ab_wasm)))
+        Self(ThreadPoolInner::new_with_arraybuffers(size, ab_js, ab_wasm).into_rc())
+    }
+
+    pub fn new_with_default_size(pkg_js_uri: &str, max: Option<usize>) -> Self {
+        Self::new(Self::default_size(max), pkg_js_uri)
+    }
+
+    // The detected core count minus one reserved for the UI thread, capped by `max`
+    pub fn default_size(max: Option<usize>) -> usize {
+        let cores = wasm_mt::utils::hardware_concurrency()
+            .unwrap_or(FALLBACK_HARDWARE_CONCURRENCY);
+        let size = cores.saturating_sub(1).max(1);
+        debug_ln!("default_size(): cores: {} size: {} max: {:?}", cores, size, max);
+
+        match max {
+            Some(max) => size.min(max.max(1)),
+            None => size,
+        }
     }
 
     pub fn set_ab_init(&self, ab: ArrayBuffer) {
         self.0.mt.set_ab_init(ab);
     }
 
+    // Run `job` on each thread before it takes any jobs, including threads
+    // spawned later, e.g. to replace a dead one; `init()` fails if `job` does.
+    pub fn with_worker_init<F>(self, job: F) -> Self where F: MtClosure {
+        let vec = bincode::serialize(&job).unwrap();
+        self.0.worker_init.replace(Some(Rc::new(move |th: Rc<Thread>| {
+            let clos: F = bincode::deserialize(&vec).unwrap();
+            future_to_promise(async move { th.exec(clos).await })
+        })));
+        self
+    }
+
+    pub fn with_worker_init_async<F, T>(self, job: F) -> Self where F: MtAsyncClosure<T> {
+        let vec = bincode::serialize(&job).unwrap();
+        self.0.worker_init.replace(Some(Rc::new(move |th: Rc<Thread>| {
+            let aclos: F = bincode::deserialize(&vec).unwrap();
+            future_to_promise(async move { th.exec_async(aclos).await })
+        })));
+        self
+    }
+
     pub async fn init(&self) -> Result<&Self, JsValue> {
         self.0.init().await?;
         Ok(self)
@@ -242,46 +961,226 @@ impl ThreadPool {
         self.0.resolver.count_pending_jobs()
     }
 
+    pub fn count_threads(&self) -> usize {
+        self.0.count_threads()
+    }
+
+    pub fn stats(&self) -> PoolStats {
+        self.0.stats()
+    }
+
+    // Let each thread run up to `concurrency` async jobs at a time, e.g. for
+    // I/O-bound jobs mostly awaiting `fetch`.  Other jobs keep running on a
+    // thread of their own.
+    pub fn set_concurrency(&self, concurrency: usize) {
+        self.0.resolver.set_concurrency(concurrency);
+        let threads: Vec<_> = self.0.threads.borrow().iter().cloned().collect();
+        threads.iter().for_each(|pth| self.0.resolver.notify_thread_ready(pth));
+    }
+
+    // Let each thread hold up to `depth` more jobs queued in its worker,
+    // saving the round trip to the main thread between jobs.  A prefetched job
+    // counts as running, so canceling it waits for the jobs ahead of it.
+    pub fn set_prefetch(&self, depth: usize) {
+        self.0.resolver.set_prefetch(depth);
+        let threads: Vec<_> = self.0.threads.borrow().iter().cloned().collect();
+        threads.iter().for_each(|pth| self.0.resolver.notify_thread_ready(pth));
+    }
+
+    // Pack up to `max` jobs submitted in a row by `exec()` and alike into a
+    // single message; `None` to disable.  A batch runs on one thread and counts
+    // as a single job in `stats()`, and its jobs are no longer cancelable once sent.
+    pub fn set_batching(&self, max: Option<usize>) {
+        assert!(max != Some(0));
+        self.0.batch_size.replace(max);
+    }
+
+    pub async fn resize(&self, size: usize) -> Result<&Self, JsValue> {
+        self.0.resize(size).await?;
+        Ok(self)
+    }
+
+    // Let the pool grow up to `max` threads while jobs are queued, and shrink
+    // back down to `min` by terminating threads idle for `idle_timeout_ms`.
+    pub fn set_autoscale(&self, min: usize, max: usize, idle_timeout_ms: u32) {
+        assert!(min <= max && max > 0);
+        self.0.autoscale.replace(Some(Autoscale { min, max, idle_timeout_ms }));
+        ThreadPoolInner::run_reaper(&self.0);
+    }
+
+    // Spawn threads on demand, up to the pool size, instead of all of them in
+    // `init()`; `standby` more are kept idle and ready ahead of demand.  `None`
+    // to spawn eagerly, which is the default.  To be called before `init()`.
+    pub fn set_lazy_spawn(&self, standby: Option<usize>) {
+        self.0.standby.replace(standby);
+    }
+
+    pub fn unset_autoscale(&self) {
+        self.0.autoscale.replace(None);
+    }
+
+    // Terminate threads idle for `ms` to free their memory, and respawn them on
+    // demand up to the pool size; `None` to keep idle threads, the default.
+    // Under autoscaling, its own idle timeout applies instead.
+    pub fn set_idle_timeout(&self, ms: Option<u32>) {
+        self.0.idle_timeout_ms.replace(ms);
+        ThreadPoolInner::run_reaper(&self.0);
+    }
+
+    // Restart a thread between jobs once it has run `max_jobs` jobs, or its
+    // wasm memory exceeds `max_memory_bytes` as sampled after its first job,
+    // then every 8 jobs or every second; `None` for both to disable
+    pub fn set_recycling(&self, max_jobs: Option<usize>, max_memory_bytes: Option<f64>) {
+        let recycle = match (max_jobs, max_memory_bytes) {
+            (None, None) => None,
+            _ => Some(Recycle { max_jobs, max_memory_bytes }),
+        };
+        self.0.recycle.replace(recycle);
+    }
+
+    pub fn set_cancel_grace_ms(&self, ms: u32) {
+        self.0.cancel_grace_ms.replace(ms);
+    }
+
+    pub fn set_dead_worker_policy(&self, policy: DeadWorkerPolicy) {
+        self.0.dead_worker_policy.replace(policy);
+    }
+
+    // Applies to jobs without a policy of their own set by `JobHandle::with_retry()`;
+    // a job failing after retries errs as its last attempt did, with the number
+    // of attempts left to `JobHandle::attempts()`
+    pub fn set_retry_policy(&self, policy: Option<RetryPolicy>) {
+        self.0.retry_policy.replace(policy);
+    }
+
+    pub fn set_event_listener<L>(&self, listener: L) where L: PoolEventListener {
+        self.0.event_listener.replace(Some(Rc::new(listener)));
+    }
+
+    pub async fn check_health(&self) -> &Self {
+        self.0.check_health().await;
+        self
+    }
+
     fn drop_cb_result(_: ResultJJ) {}
 
-    pub fn exec<F>(&self, job: F) where F: MtClosure {
-        self.exec_with_cb(job, Self::drop_cb_result);
+    // Returns the results of all nodes, indexed by `NodeId::index()`
+    pub async fn run_graph(&self, graph: TaskGraph) -> Vec<ResultJJ> {
+        graph.run(self.0.clone()).await
+    }
+
+    pub fn exec<F>(&self, job: F) -> JobHandle where F: MtClosure {
+        self.exec_with_cb(job, Self::drop_cb_result)
     }
-    pub fn exec_async<F, T>(&self, job: F) where F: MtAsyncClosure<T> {
-        self.exec_async_with_cb(job, Self::drop_cb_result);
+    pub fn exec_async<F, T>(&self, job: F) -> JobHandle where F: MtAsyncClosure<T> {
+        self.exec_async_with_cb(job, Self::drop_cb_result)
     }
-    pub fn exec_with_cb<F, G>(&self, job: F, cb: G) where
+    pub fn exec_with_cb<F, G>(&self, job: F, cb: G) -> JobHandle where
     F: MtClosure, G: PoolCallback {
         let pool_inner = self.0.clone();
+        let state = pool_inner.new_job(false);
+        let handle = JobHandle(state.clone());
+        if pool_inner.batch_size.borrow().is_some() {
+            pool_inner.push_batch(batch::Member::new(clos_to_ab(job), state, Box::new(cb)));
+            return handle;
+        }
         spawn_local(async move {
-            cb(pool_inner.execute(job).await);
+            cb(pool_inner.execute(job, &state).await);
         });
+        handle
     }
-    pub fn exec_async_with_cb<F, T, G>(&self, job: F, cb: G) where
+    pub fn exec_async_with_cb<F, T, G>(&self, job: F, cb: G) -> JobHandle where
     F: MtAsyncClosure<T>, G: PoolCallback {
         let pool_inner = self.0.clone();
+        let state = pool_inner.new_job(true);
+        let handle = JobHandle(state.clone());
         spawn_local(async move {
-            cb(pool_inner.execute_async(job).await);
+            cb(pool_inner.execute_async(job, &state).await);
         });
+        handle
+    }
+
+    // Run the job once on each thread currently in the pool, waiting for busy
+    // threads to free up; the results are in the order of `stats().threads`.
+    pub async fn broadcast<F>(&self, job: F) -> Vec<ResultJJ> where F: MtClosure {
+        self.0.broadcast(false, clos_to_ab(job)).await
+    }
+
+    pub async fn broadcast_async<F, T>(&self, job: F) -> Vec<ResultJJ> where F: MtAsyncClosure<T> {
+        self.0.broadcast(true, aclos_to_ab(job)).await
+    }
+
+    // Limit the number of submitted jobs left waiting for a thread; `None` for unbounded
+    pub fn set_queue_capacity(&self, capacity: Option<usize>) {
+        self.0.resolver.set_capacity(capacity);
+    }
+
+    pub fn is_queue_full(&self) -> bool {
+        self.0.is_queue_full()
+    }
+
+    // Wait until the queue has space for one more job
+    pub async fn reserve(&self) -> Result<(), JsValue> {
+        while self.0.is_queue_full() {
+            self.0.resolver.wait_for_space().await?;
+        }
+        Ok(())
+    }
+
+    pub fn try_exec<F>(&self, job: F) -> Result<JobHandle, JsValue> where F: MtClosure {
+        self.try_exec_with_cb(job, Self::drop_cb_result)
+    }
+    pub fn try_exec_async<F, T>(&self, job: F) -> Result<JobHandle, JsValue> where F: MtAsyncClosure<T> {
+        self.try_exec_async_with_cb(job, Self::drop_cb_result)
+    }
+    pub fn try_exec_with_cb<F, G>(&self, job: F, cb: G) -> Result<JobHandle, JsValue> where
+    F: MtClosure, G: PoolCallback {
+        self.0.check_queue()?;
+        Ok(self.exec_with_cb(job, cb))
+    }
+    pub fn try_exec_async_with_cb<F, T, G>(&self, job: F, cb: G) -> Result<JobHandle, JsValue> where
+    F: MtAsyncClosure<T>, G: PoolCallback {
+        self.0.check_queue()?;
+        Ok(self.exec_async_with_cb(job, cb))
+    }
+
+    pub async fn exec_when_ready<F>(&self, job: F) -> Result<JobHandle, JsValue> where F: MtClosure {
+        self.exec_when_ready_with_cb(job, Self::drop_cb_result).await
+    }
+    pub async fn exec_async_when_ready<F, T>(&self, job: F) -> Result<JobHandle, JsValue> where F: MtAsyncClosure<T> {
+        self.exec_async_when_ready_with_cb(job, Self::drop_cb_result).await
+    }
+    pub async fn exec_when_ready_with_cb<F, G>(&self, job: F, cb: G) -> Result<JobHandle, JsValue> where
+    F: MtClosure, G: PoolCallback {
+        self.reserve().await?;
+        Ok(self.exec_with_cb(job, cb))
+    }
+    pub async fn exec_async_when_ready_with_cb<F, T, G>(&self, job: F, cb: G) -> Result<JobHandle, JsValue> where
+    F: MtAsyncClosure<T>, G: PoolCallback {
+        self.reserve().await?;
+        Ok(self.exec_async_with_cb(job, cb))
     }
 
-    pub fn exec_js(&self, js: &str) {
-        self.exec_js_inner(js, false, Self::drop_cb_result);
+    pub fn exec_js(&self, js: &str) -> JobHandle {
+        self.exec_js_inner(js, false, Self::drop_cb_result)
     }
-    pub fn exec_js_async(&self, js: &str) {
-        self.exec_js_inner(js, true, Self::drop_cb_result);
+    pub fn exec_js_async(&self, js: &str) -> JobHandle {
+        self.exec_js_inner(js, true, Self::drop_cb_result)
     }
-    pub fn exec_js_with_cb<G>(&self, js: &str, cb: G) where G: PoolCallback {
-        self.exec_js_inner(js, false, cb);
+    pub fn exec_js_with_cb<G>(&self, js: &str, cb: G) -> JobHandle where G: PoolCallback {
+        self.exec_js_inner(js, false, cb)
     }
-    pub fn exec_js_async_with_cb<G>(&self, js: &str, cb: G) where G: PoolCallback {
-        self.exec_js_inner(js, true, cb);
+    pub fn exec_js_async_with_cb<G>(&self, js: &str, cb: G) -> JobHandle where G: PoolCallback {
+        self.exec_js_inner(js, true, cb)
     }
-    fn exec_js_inner<G>(&self, js: &str, is_async: bool, cb: G) where G: PoolCallback {
+    fn exec_js_inner<G>(&self, js: &str, is_async: bool, cb: G) -> JobHandle where G: PoolCallback {
         let pool_inner = self.0.clone();
         let js = js.to_string();
+        let state = pool_inner.new_job(is_async);
+        let handle = JobHandle(state.clone());
         spawn_local(async move {
-            cb(pool_inner.execute_js(js.as_str(), is_async).await);
+            cb(pool_inner.execute_js(js, is_async, &state).await);
         });
+        handle
     }
 }
@@ -0,0 +1,90 @@
+use wasm_mt::MtClosure;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::{future_to_promise, JsFuture};
+use js_sys::{Array, Promise, Uint8Array};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_closure::FnOnce;
+use super::ThreadPool;
+
+pub trait ParItem = Serialize + DeserializeOwned + 'static;
+pub trait ParFn<T, U> = Fn(T,) -> U + Serialize + DeserializeOwned + 'static;
+pub trait ParReduceFn<T> = Fn(T, T,) -> T + Serialize + DeserializeOwned + 'static;
+
+fn encode<R>(ret: &R) -> JsValue where R: Serialize {
+    JsValue::from(Uint8Array::from(&bincode::serialize(ret).unwrap()[..]))
+}
+
+fn decode<R>(jsv: &JsValue) -> R where R: DeserializeOwned {
+    bincode::deserialize(&Uint8Array::new(jsv).to_vec()).unwrap()
+}
+
+// Split `items` into at most `num` contiguous batches of nearly equal length
+fn split<T>(items: Vec<T>, num: usize) -> Vec<Vec<T>> {
+    let len = items.len().div_ceil(num).max(1);
+    let mut batches = Vec::new();
+    let mut items = items.into_iter().peekable();
+    while items.peek().is_some() {
+        batches.push(items.by_ref().take(len).collect());
+    }
+    batches
+}
+
+impl ThreadPool {
+    // One batch per thread, each returning its result encoded by `encode()`
+    async fn run_batches<F, R>(&self, jobs: Vec<F>) -> Result<Vec<R>, JsValue> where F: MtClosure, R: DeserializeOwned {
+        let promises: Array = jobs.into_iter().map(|job| {
+            let pool_inner = self.0.clone();
+            future_to_promise(async move {
+                let state = pool_inner.new_job(false);
+                pool_inner.execute(job, &state).await
+            })
+        }).collect();
+
+        let outputs = JsFuture::from(Promise::all(&promises)).await?;
+        Ok(outputs.dyn_into::<Array>().unwrap().iter().map(|jsv| decode(&jsv)).collect())
+    }
+
+    fn count_batches(&self) -> usize {
+        self.0.count_threads().max(1)
+    }
+
+    // Results are in the order of `items`
+    pub async fn par_map<T, U, F>(&self, items: Vec<T>, f: F) -> Result<Vec<U>, JsValue> where
+    T: ParItem, U: ParItem, F: ParFn<T, U> {
+        // Kept serialized so that each batch gets a copy of `f`
+        let vec = bincode::serialize(&f).unwrap();
+        let jobs: Vec<_> = split(items, self.count_batches()).into_iter().map(|batch| {
+            let f: F = bincode::deserialize(&vec).unwrap();
+            FnOnce!(move || {
+                let out: Vec<U> = batch.into_iter().map(&f).collect();
+                Ok(encode(&out))
+            })
+        }).collect();
+
+        let outs: Vec<Vec<U>> = self.run_batches(jobs).await?;
+        Ok(outs.into_iter().flatten().collect())
+    }
+
+    // `f` maps each chunk of `chunk_size` items (the last one may be shorter)
+    pub async fn par_chunks<T, U, F>(&self, items: &[T], chunk_size: usize, f: F) -> Result<Vec<U>, JsValue> where
+    T: ParItem + Clone, U: ParItem, F: ParFn<Vec<T>, U> {
+        assert!(chunk_size > 0);
+        self.par_map(items.chunks(chunk_size).map(|chunk| chunk.to_vec()).collect(), f).await
+    }
+
+    // `f` should be associative with `identity` as its identity element, as
+    // batches are folded on the threads before their results are folded here.
+    pub async fn par_reduce<T, F>(&self, items: Vec<T>, identity: T, f: F) -> Result<T, JsValue> where
+    T: ParItem + Clone, F: ParReduceFn<T> {
+        let vec = bincode::serialize(&f).unwrap();
+        let jobs: Vec<_> = split(items, self.count_batches()).into_iter().map(|batch| {
+            let f: F = bincode::deserialize(&vec).unwrap();
+            let identity = identity.clone();
+            FnOnce!(move || Ok(encode(&batch.into_iter().fold(identity, &f))))
+        }).collect();
+
+        let partials: Vec<T> = self.run_batches(jobs).await?;
+        Ok(partials.into_iter().fold(identity, &f))
+    }
+}
@@ -1,2 +1,2 @@
-pub use super::{ThreadPool, pool_exec, pool_exec_js, pool_exec_js_async};
-pub use super::wasm_mt::prelude::FnOnce;
+pub use super::{ThreadPool, JobHandle, JobStatus, PoolEvent, DeadWorkerPolicy, PoolStats, RetryPolicy, RaceJob, TaskGraph, NodeId, Scope, pool_exec, pool_exec_js, pool_exec_js_async};
+pub use super::wasm_mt::prelude::{Fn, FnOnce};
@@ -0,0 +1,105 @@
+use wasm_mt::{Thread, MtClosure, MtAsyncClosure, clos_to_ab, aclos_to_ab};
+use wasm_mt::utils::sleep;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::{future_to_promise, spawn_local, JsFuture};
+use js_sys::{ArrayBuffer, Promise};
+use std::rc::Rc;
+use std::cell::RefCell;
+use super::{ResultJJ, ThreadPool, JobHandle};
+
+// A job of any closure type, so that jobs of different types can be raced
+#[derive(Clone)]
+pub struct RaceJob {
+    ab: ArrayBuffer,
+    is_shared: bool,
+}
+
+impl RaceJob {
+    pub fn new<F>(job: F) -> Self where F: MtClosure {
+        Self { ab: clos_to_ab(job), is_shared: false }
+    }
+
+    pub fn new_async<F, T>(job: F) -> Self where F: MtAsyncClosure<T> {
+        Self { ab: aclos_to_ab(job), is_shared: true }
+    }
+}
+
+impl ThreadPool {
+    // `reserved` is a thread already occupied for the job
+    fn submit(&self, job: RaceJob, reserved: Option<Rc<Thread>>) -> (JobHandle, Promise) {
+        let pool_inner = self.0.clone();
+        let state = pool_inner.new_job(job.is_shared);
+        if let Some(pth) = reserved {
+            state.set_reserved(pth);
+        }
+        let handle = JobHandle(state.clone());
+        (handle, future_to_promise(async move { pool_inner.execute_ab(job.ab, &state).await }))
+    }
+
+    // Settles with the first success, or the last error if all fail; the
+    // remaining jobs get canceled either way
+    async fn race_jobs(&self, jobs: Vec<(JobHandle, Promise)>) -> ResultJJ {
+        assert!(!jobs.is_empty());
+
+        let mut settle = None;
+        let first = Promise::new(&mut |res, rej| settle = Some((res, rej)));
+        let (res, rej) = settle.unwrap();
+        let num_failed = Rc::new(RefCell::new(0));
+        for (_, promise) in jobs.iter() {
+            let (num, promise) = (jobs.len(), promise.clone());
+            let (res, rej, num_failed) = (res.clone(), rej.clone(), num_failed.clone());
+            spawn_local(async move {
+                match JsFuture::from(promise).await {
+                    Ok(jsv) => {
+                        res.call1(&JsValue::NULL, &jsv).unwrap();
+                    },
+                    Err(jsv) => {
+                        *num_failed.borrow_mut() += 1;
+                        if *num_failed.borrow() == num {
+                            rej.call1(&JsValue::NULL, &jsv).unwrap();
+                        }
+                    },
+                }
+            });
+        }
+
+        let result = JsFuture::from(first).await;
+        jobs.iter().for_each(|(handle, _)| handle.cancel());
+        result
+    }
+
+    // The losers get canceled, i.e. dropped from the queue or aborted by their workers
+    pub async fn race(&self, jobs: Vec<RaceJob>) -> ResultJJ {
+        let jobs = jobs.into_iter().map(|job| self.submit(job, None)).collect();
+        self.race_jobs(jobs).await
+    }
+
+    // Whether `promise` settles within `ms`
+    async fn settles_within(promise: &Promise, ms: u32) -> bool {
+        let promise = promise.clone();
+        let settled = future_to_promise(async move {
+            let _ = JsFuture::from(promise).await;
+            Ok(JsValue::TRUE)
+        });
+        let timeout = future_to_promise(async move {
+            sleep(ms).await;
+            Ok(JsValue::FALSE)
+        });
+        super::race(&settled, &timeout).await == Ok(JsValue::TRUE)
+    }
+
+    // If the job is still running after `delay_ms`, a duplicate is started on
+    // an idle thread other than its own, if any, and the first result of the
+    // two wins.
+    pub async fn hedge(&self, job: RaceJob, delay_ms: u32) -> ResultJJ {
+        let mut jobs = vec![self.submit(job.clone(), None)];
+        if !Self::settles_within(&jobs[0].1, delay_ms).await {
+            let pth = (jobs[0].0).0.last_thread_id()
+                .and_then(|avoid| self.0.reserve_idle_thread(job.is_shared, &avoid));
+            if let Some(pth) = pth {
+                jobs.push(self.submit(job, Some(pth)));
+            }
+        }
+        self.race_jobs(jobs).await
+    }
+}
@@ -2,46 +2,245 @@ use wasm_mt::{debug_ln, Thread};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::JsFuture;
 use js_sys::{Promise, Function};
-use std::collections::VecDeque;
+use std::collections::{HashMap, VecDeque};
 use std::cell::RefCell;
+use std::rc::Rc;
+
+struct Waiter {
+    job_id: usize,
+    is_shared: bool,
+    avoid: Option<String>,
+    res: Function,
+    rej: Function,
+}
+
+// Jobs in flight on a thread.  Async jobs can share a thread up to the
+// concurrency limit, while any other job occupies a thread exclusively,
+// except for up to `prefetch` more such jobs queued in its worker.
+#[derive(Clone, Copy, Default)]
+struct Load {
+    shared: usize,
+    exclusive: usize,
+}
+
+impl Load {
+    fn is_idle(&self) -> bool {
+        self.shared == 0 && self.exclusive == 0
+    }
+}
+
+fn thread_id(pth: &Thread) -> String {
+    pth.get_id().unwrap().to_string()
+}
 
 pub struct Resolver {
-    queue: RefCell<VecDeque<(Function, Function)>>,
+    queue: RefCell<VecDeque<Waiter>>,
+    capacity: RefCell<Option<usize>>,
+    num_waiting: RefCell<usize>,
+    space_waiters: RefCell<VecDeque<(Function, Function)>>,
+    concurrency: RefCell<usize>,
+    prefetch: RefCell<usize>,
+    loads: RefCell<HashMap<String, Load>>,
+    pinned: RefCell<HashMap<String, VecDeque<Waiter>>>,
 }
 
 impl Resolver {
     pub fn new() -> Resolver {
         Resolver {
             queue: RefCell::new(VecDeque::new()),
+            capacity: RefCell::new(None),
+            num_waiting: RefCell::new(0),
+            space_waiters: RefCell::new(VecDeque::new()),
+            concurrency: RefCell::new(1),
+            prefetch: RefCell::new(0),
+            loads: RefCell::new(HashMap::new()),
+            pinned: RefCell::new(HashMap::new()),
         }
     }
 
-    pub async fn resolve_runnable<'a>(&self, threads: &'a Vec<Thread>) -> Result<&'a Thread, JsValue> {
-        for pth in threads.iter() {
-            if !pth.get_busy() {
-                debug_ln!("[resolver] immediate resolution to pth: {}", pth.get_id().unwrap());
-                return Ok(pth.set_busy(true));
-            }
+    pub fn set_capacity(&self, capacity: Option<usize>) {
+        self.capacity.replace(capacity);
+        while self.notify_space() {}
+    }
+
+    pub fn set_concurrency(&self, concurrency: usize) {
+        assert!(concurrency > 0);
+        self.concurrency.replace(concurrency);
+    }
+
+    pub fn set_prefetch(&self, prefetch: usize) {
+        self.prefetch.replace(prefetch);
+    }
+
+    // Jobs are counted as waiting from their submission until a thread is
+    // resolved for them, so that a burst of synchronous submissions is
+    // accounted for before any of them reaches `resolve_runnable()`.
+    pub fn enter_waiting(&self) {
+        *self.num_waiting.borrow_mut() += 1;
+    }
+
+    pub fn leave_waiting(&self) {
+        *self.num_waiting.borrow_mut() -= 1;
+        self.notify_space();
+    }
+
+    pub fn is_full(&self, threads: &RefCell<Vec<Rc<Thread>>>) -> bool {
+        match *self.capacity.borrow() {
+            Some(cap) => *self.num_waiting.borrow() >= self.count_free_slots(threads) + cap,
+            None => false,
+        }
+    }
+
+    pub async fn wait_for_space(&self) -> Result<(), JsValue> {
+        let promise = Promise::new(&mut |res, rej| self.space_waiters.borrow_mut().push_back((res, rej)));
+        JsFuture::from(promise).await.map(|_| ())
+    }
+
+    fn notify_space(&self) -> bool {
+        let waiter = self.space_waiters.borrow_mut().pop_front();
+        match waiter {
+            Some((res, _rej)) => {
+                res.call0(&JsValue::NULL).unwrap();
+                true
+            },
+            None => false,
+        }
+    }
+
+    fn load_of(&self, id: &str) -> Load {
+        self.loads.borrow().get(id).cloned().unwrap_or_default()
+    }
+
+    fn fits(&self, id: &str, is_shared: bool) -> bool {
+        let load = self.load_of(id);
+        if is_shared {
+            load.exclusive == 0 && load.shared < *self.concurrency.borrow()
+        } else {
+            load.shared == 0 && load.exclusive <= *self.prefetch.borrow()
+        }
+    }
+
+    fn occupy(&self, pth: &Thread, is_shared: bool) {
+        let mut loads = self.loads.borrow_mut();
+        let load = loads.entry(thread_id(pth)).or_default();
+        if is_shared {
+            load.shared += 1;
+        } else {
+            load.exclusive += 1;
+        }
+        pth.set_busy(true);
+    }
+
+    // Returns whether the thread has become idle
+    pub fn vacate(&self, pth: &Thread, is_shared: bool) -> bool {
+        let mut loads = self.loads.borrow_mut();
+        let load = loads.entry(thread_id(pth)).or_default();
+        if is_shared {
+            load.shared = load.shared.saturating_sub(1);
+        } else {
+            load.exclusive = load.exclusive.saturating_sub(1);
+        }
+        load.is_idle()
+    }
+
+    pub fn forget_thread(&self, pth: &Thread) {
+        let id = thread_id(pth);
+        self.loads.borrow_mut().remove(&id);
+
+        // With the threads changed, the one to avoid may be the only one left
+        self.queue.borrow_mut().iter_mut().for_each(|waiter| waiter.avoid = None);
+
+        let pinned = self.pinned.borrow_mut().remove(&id);
+        for Waiter { rej, .. } in pinned.into_iter().flatten() {
+            rej.call1(&JsValue::NULL,
+                &JsValue::from(format!("ThreadPool: thread[{}] removed", id))).unwrap();
+        }
+    }
+
+    pub fn count_in_flight(&self, pth: &Thread) -> usize {
+        let load = self.load_of(&thread_id(pth));
+        load.shared + load.exclusive
+    }
+
+    pub fn count_free_slots(&self, threads: &RefCell<Vec<Rc<Thread>>>) -> usize {
+        let concurrency = *self.concurrency.borrow();
+        let depth = *self.prefetch.borrow() + 1;
+        threads.borrow().iter()
+            .map(|pth| self.load_of(&thread_id(pth)))
+            .map(|load| if load.exclusive > 0 {
+                depth.saturating_sub(load.exclusive)
+            } else if load.shared > 0 {
+                concurrency.saturating_sub(load.shared)
+            } else {
+                concurrency.max(depth)
+            })
+            .sum()
+    }
+
+    pub fn has_runnable(&self, threads: &RefCell<Vec<Rc<Thread>>>, is_shared: bool) -> bool {
+        threads.borrow().iter().any(|pth| self.fits(&thread_id(pth), is_shared))
+    }
+
+    // Occupy a thread the job fits in right away, if any; one other than
+    // `avoid`, e.g. for retrying a job, unless there is no other thread
+    pub fn try_resolve_runnable(&self, threads: &RefCell<Vec<Rc<Thread>>>, is_shared: bool, avoid: Option<&str>) -> Option<Rc<Thread>> {
+        let avoid = avoid.filter(|_| threads.borrow().len() > 1);
+        let found = threads.borrow().iter()
+            .filter(|pth| avoid != Some(thread_id(pth).as_str()))
+            .filter(|pth| self.fits(&thread_id(pth), is_shared))
+            .min_by_key(|pth| {
+                let load = self.load_of(&thread_id(pth));
+                load.shared + load.exclusive
+            })
+            .cloned();
+        if let Some(ref pth) = found {
+            debug_ln!("[resolver] immediate resolution to pth: {}", pth.get_id().unwrap());
+            self.occupy(pth, is_shared);
+        }
+        found
+    }
+
+    // Occupy an idle thread other than `avoid` right away, if any, e.g. for
+    // running a duplicate of a job
+    pub fn try_resolve_idle(&self, threads: &RefCell<Vec<Rc<Thread>>>, is_shared: bool, avoid: &str) -> Option<Rc<Thread>> {
+        let found = threads.borrow().iter()
+            .find(|pth| thread_id(pth) != avoid && self.load_of(&thread_id(pth)).is_idle())
+            .cloned();
+        if let Some(ref pth) = found {
+            debug_ln!("[resolver] idle resolution to pth: {}", pth.get_id().unwrap());
+            self.occupy(pth, is_shared);
+        }
+        found
+    }
+
+    pub async fn resolve_runnable(&self, threads: &RefCell<Vec<Rc<Thread>>>, job_id: usize, is_shared: bool, avoid: Option<&str>) -> Result<Rc<Thread>, JsValue> {
+        if let Some(pth) = self.try_resolve_runnable(threads, is_shared, avoid) {
+            return Ok(pth);
         }
 
         debug_ln!("[resolver] deferring resolution... pth: ?");
-        let promise = Promise::new(&mut |res, rej| self.queue.borrow_mut().push_back((res, rej)));
+        let avoid = avoid.filter(|_| threads.borrow().len() > 1).map(String::from);
+        let promise = Promise::new(&mut |res, rej| {
+            self.queue.borrow_mut().push_back(Waiter { job_id, is_shared, avoid: avoid.clone(), res, rej });
+        });
 
         match JsFuture::from(promise).await {
             Ok(ref jsv) => {
-                let id = jsv.as_string().unwrap()
-                    .parse::<usize>().unwrap();
+                let id = jsv.as_string().unwrap();
 
                 debug_ln!("[resolver] a queued promise resolved to id: {}", id);
 
-                let pth = &threads[id];
-                if !pth.get_busy() {
-                    let msg = "[resolver] the resolved thread is in illegal state";
-                    debug_ln!("calling `panic!()`: {}", msg);
-                    panic!("{}", msg);
+                let threads = threads.borrow();
+                let pth = threads.iter()
+                    .find(|pth| *pth.get_id().unwrap() == id);
+                match pth {
+                    Some(pth) if pth.get_busy() => Ok(pth.clone()),
+                    _ => {
+                        let msg = "[resolver] the resolved thread is in illegal state";
+                        debug_ln!("calling `panic!()`: {}", msg);
+                        panic!("{}", msg);
+                    },
                 }
-
-                Ok(pth)
             },
             Err(jsv) => {
                 debug_ln!("[resolver] a queued promise rejected with: {:?}", jsv);
@@ -51,13 +250,56 @@ impl Resolver {
         }
     }
 
-    pub fn notify_job_complete(&self, pth: &Thread) {
-        if let Some((res, _rej)) = self.queue.borrow_mut().pop_front() {
-            // let the pending `resolve_runnable()` return for one more round to go
-            res.call1(&JsValue::NULL, &JsValue::from(pth.get_id().unwrap().as_ref())).unwrap();
-        } else {
+    // Occupy the given thread, bypassing the queue of jobs for any thread
+    pub async fn resolve_thread(&self, pth: &Thread, job_id: usize, is_shared: bool) -> Result<(), JsValue> {
+        let id = thread_id(pth);
+        if self.fits(&id, is_shared) {
+            self.occupy(pth, is_shared);
+            return Ok(());
+        }
+
+        let promise = Promise::new(&mut |res, rej| {
+            self.pinned.borrow_mut().entry(id.clone()).or_default()
+                .push_back(Waiter { job_id, is_shared, avoid: None, res, rej });
+        });
+        JsFuture::from(promise).await.map(|_| ())
+    }
+
+    // The first waiter not avoiding the thread, if it fits
+    fn pop_fitting(&self, queue: &mut VecDeque<Waiter>, id: &str) -> Option<Waiter> {
+        let pos = queue.iter()
+            .position(|waiter| waiter.avoid.as_deref() != Some(id))
+            .filter(|pos| self.fits(id, queue[*pos].is_shared));
+        pos.and_then(|pos| queue.remove(pos))
+    }
+
+    // Hand the thread over to as many queued jobs as it has room for, those
+    // pinned to it first; also used for a freshly spawned thread
+    pub fn notify_thread_ready(&self, pth: &Thread) {
+        let id = thread_id(pth);
+        loop {
+            let waiter = {
+                let mut pinned = self.pinned.borrow_mut();
+                match pinned.get_mut(&id) {
+                    Some(queue) if !queue.is_empty() => self.pop_fitting(queue, &id),
+                    _ => self.pop_fitting(&mut self.queue.borrow_mut(), &id),
+                }
+            };
+
+            match waiter {
+                Some(Waiter { is_shared, res, .. }) => {
+                    // let the pending `resolve_runnable()` return for one more round to go
+                    self.occupy(pth, is_shared);
+                    res.call1(&JsValue::NULL, &JsValue::from(&id)).unwrap();
+                },
+                None => break,
+            }
+        }
+
+        if self.load_of(&id).is_idle() {
             pth.set_busy(false);
         }
+        self.notify_space();
     }
 
     pub fn cancel_pending_jobs(&self) {
@@ -65,15 +307,41 @@ impl Resolver {
 
         let cancels = queue.len();
         debug_ln!("cancel_pending_jobs(): canceling {} pending jobs", cancels);
-        let mut count = 0;
-        while let Some((_res, rej)) = queue.pop_front() {
+        while let Some(Waiter { job_id, rej, .. }) = queue.pop_front() {
             rej.call1(&JsValue::NULL,
-                &JsValue::from(format!("ThreadPool: job[{}] canceled", count))).unwrap();
-            count += 1;
+                &JsValue::from(format!("ThreadPool: job[{}] canceled", job_id))).unwrap();
+        }
+
+        for (_, pinned) in self.pinned.borrow_mut().drain() {
+            for Waiter { job_id, rej, .. } in pinned {
+                rej.call1(&JsValue::NULL,
+                    &JsValue::from(format!("ThreadPool: job[{}] canceled", job_id))).unwrap();
+            }
+        }
+
+        let mut space_waiters = self.space_waiters.borrow_mut();
+        while let Some((_res, rej)) = space_waiters.pop_front() {
+            rej.call1(&JsValue::NULL,
+                &JsValue::from("ThreadPool: reservation canceled")).unwrap();
+        }
+    }
+
+    pub fn cancel_job(&self, job_id: usize) -> bool {
+        let mut queue = self.queue.borrow_mut();
+        match queue.iter().position(|waiter| waiter.job_id == job_id) {
+            Some(pos) => {
+                let waiter = queue.remove(pos).unwrap();
+                debug_ln!("cancel_job(): canceling queued job: {}", job_id);
+                waiter.rej.call1(&JsValue::NULL,
+                    &JsValue::from(format!("ThreadPool: job[{}] canceled", job_id))).unwrap();
+                true
+            },
+            None => false,
         }
     }
 
     pub fn count_pending_jobs(&self) -> usize {
-        self.queue.borrow().len()
+        self.queue.borrow().len() +
+            self.pinned.borrow().values().map(|queue| queue.len()).sum::<usize>()
     }
 }
@@ -0,0 +1,52 @@
+use wasm_bindgen::prelude::*;
+use std::rc::Rc;
+
+pub trait RetryPredicate = Fn(&JsValue,) -> bool + 'static;
+
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: usize,
+    backoff_ms: u32,
+    backoff_factor: f64,
+    predicate: Option<Rc<dyn Fn(&JsValue) -> bool>>,
+}
+
+impl RetryPolicy {
+    // Retries any error immediately until `max_attempts` runs in total
+    pub fn new(max_attempts: usize) -> Self {
+        assert!(max_attempts > 0);
+        Self {
+            max_attempts,
+            backoff_ms: 0,
+            backoff_factor: 1.0,
+            predicate: None,
+        }
+    }
+
+    // Wait `ms` before the first retry, multiplied by `factor` for each further retry
+    pub fn with_backoff(mut self, ms: u32, factor: f64) -> Self {
+        self.backoff_ms = ms;
+        self.backoff_factor = factor;
+        self
+    }
+
+    // Only retry errors for which `predicate` returns `true`
+    pub fn retry_if<P>(mut self, predicate: P) -> Self where P: RetryPredicate {
+        self.predicate = Some(Rc::new(predicate));
+        self
+    }
+
+    pub fn max_attempts(&self) -> usize {
+        self.max_attempts
+    }
+
+    pub fn should_retry(&self, attempts: usize, err: &JsValue) -> bool {
+        attempts < self.max_attempts &&
+            self.predicate.as_ref().map_or(true, |pred| pred(err))
+    }
+
+    pub fn delay_ms(&self, attempts: usize) -> u32 {
+        let exp = attempts.saturating_sub(1) as i32;
+        (self.backoff_ms as f64 * self.backoff_factor.powi(exp)).min(u32::MAX as f64) as u32
+    }
+}
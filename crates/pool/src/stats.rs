@@ -0,0 +1,140 @@
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::cell::RefCell;
+
+// Number of most recent jobs the latency percentiles are computed over
+const MAX_SAMPLES: usize = 1024;
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Percentiles {
+    pub p50: f64,
+    pub p90: f64,
+    pub p99: f64,
+    pub max: f64,
+}
+
+impl Percentiles {
+    fn from_samples(samples: &VecDeque<f64>) -> Self {
+        if samples.is_empty() {
+            return Self::default();
+        }
+
+        let mut sorted: Vec<f64> = samples.iter().cloned().collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        let at = |p: f64| sorted[((sorted.len() - 1) as f64 * p).round() as usize];
+
+        Self {
+            p50: at(0.5),
+            p90: at(0.9),
+            p99: at(0.99),
+            max: sorted[sorted.len() - 1],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ThreadStats {
+    pub id: String,
+    pub is_busy: bool,
+    pub in_flight: usize,
+    pub jobs_completed: usize,
+    pub jobs_failed: usize,
+    pub busy_ms: f64,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PoolStats {
+    pub threads: Vec<ThreadStats>,
+    pub pending_jobs: usize,
+    pub jobs_completed: usize,
+    pub jobs_failed: usize,
+    pub jobs_canceled: usize,
+    pub queue_wait_ms: Percentiles,
+    pub run_ms: Percentiles,
+}
+
+#[derive(Default)]
+struct Record {
+    threads: HashMap<String, ThreadStats>,
+    jobs_completed: usize,
+    jobs_failed: usize,
+    jobs_canceled: usize,
+    wait_samples: VecDeque<f64>,
+    run_samples: VecDeque<f64>,
+}
+
+fn push_sample(samples: &mut VecDeque<f64>, ms: f64) {
+    if samples.len() == MAX_SAMPLES {
+        samples.pop_front();
+    }
+    samples.push_back(ms);
+}
+
+pub struct Stats(RefCell<Record>);
+
+impl Stats {
+    pub fn new() -> Self {
+        Self(RefCell::new(Record::default()))
+    }
+
+    pub fn record_wait(&self, ms: f64) {
+        push_sample(&mut self.0.borrow_mut().wait_samples, ms);
+    }
+
+    // Called per run, i.e. possibly more than once per job
+    pub fn record_run(&self, thread_id: &str, ms: f64, is_ok: bool) {
+        let mut rec = self.0.borrow_mut();
+        push_sample(&mut rec.run_samples, ms);
+
+        let ths = rec.threads.entry(thread_id.to_string())
+            .or_insert_with(|| ThreadStats { id: thread_id.to_string(), ..Default::default() });
+        ths.busy_ms += ms;
+        if is_ok {
+            ths.jobs_completed += 1;
+        } else {
+            ths.jobs_failed += 1;
+        }
+    }
+
+    // Called once per job with its final outcome; `None` for canceled
+    pub fn record_outcome(&self, is_ok: Option<bool>) {
+        let mut rec = self.0.borrow_mut();
+        match is_ok {
+            Some(true) => rec.jobs_completed += 1,
+            Some(false) => rec.jobs_failed += 1,
+            None => rec.jobs_canceled += 1,
+        }
+    }
+
+    // Number of runs, whether ok or not, on the thread
+    pub fn count_runs(&self, thread_id: &str) -> usize {
+        self.0.borrow().threads.get(thread_id)
+            .map_or(0, |ths| ths.jobs_completed + ths.jobs_failed)
+    }
+
+    pub fn forget_thread(&self, thread_id: &str) {
+        self.0.borrow_mut().threads.remove(thread_id);
+    }
+
+    // `live` lists the ids and numbers of jobs in flight of the threads currently in the pool
+    pub fn snapshot(&self, live: &[(String, usize)], pending_jobs: usize) -> PoolStats {
+        let rec = self.0.borrow();
+        let threads = live.iter().map(|(id, in_flight)| {
+            let mut ths = rec.threads.get(id).cloned()
+                .unwrap_or_else(|| ThreadStats { id: id.clone(), ..Default::default() });
+            ths.is_busy = *in_flight > 0;
+            ths.in_flight = *in_flight;
+            ths
+        }).collect();
+
+        PoolStats {
+            threads,
+            pending_jobs,
+            jobs_completed: rec.jobs_completed,
+            jobs_failed: rec.jobs_failed,
+            jobs_canceled: rec.jobs_canceled,
+            queue_wait_ms: Percentiles::from_samples(&rec.wait_samples),
+            run_ms: Percentiles::from_samples(&rec.run_samples),
+        }
+    }
+}
@@ -4,9 +4,12 @@ use wasm_bindgen_test::*;
 wasm_bindgen_test_configure!(run_in_browser);
 
 use wasm_bindgen::prelude::*;
+use js_sys::Reflect;
 use wasm_mt::{utils, utils::console_ln};
 use wasm_mt_pool::prelude::*;
-use wasm_mt_pool_test::create_pool;
+use wasm_mt_pool_test::{create_pool, new_pool};
+use std::cell::RefCell;
+use std::rc::Rc;
 
 async fn sleep(ms: u32) {
     console_ln!("sleeping for {}ms...", ms);
@@ -96,3 +99,533 @@ async fn pool() {
         console_ln!("`pool` is being dropped!!");
     }
 }
+
+#[wasm_bindgen_test]
+async fn resize() {
+    let pool = create_pool(2).await;
+    assert_eq!(pool.count_threads(), 2);
+
+    pool.resize(4).await.unwrap();
+    assert_eq!(pool.count_threads(), 4);
+
+    for _ in 0..8 {
+        pool_exec!(pool, move || Ok(JsValue::from(42)));
+    }
+    // The jobs are yet to be dispatched, so the excess threads are idle and get
+    // terminated right away; busy ones would retire as their jobs complete.
+    pool.resize(1).await.unwrap();
+    assert_eq!(pool.count_threads(), 1);
+
+    sleep(500).await;
+    assert_eq!(pool.count_pending_jobs(), 0);
+    assert_eq!(pool.count_threads(), 1);
+
+    assert!(pool.resize(0).await.is_err());
+    assert_eq!(pool.count_threads(), 1);
+}
+
+#[wasm_bindgen_test]
+async fn autoscale() {
+    let pool = create_pool(1).await;
+    pool.set_autoscale(1, 3, 200);
+
+    for _ in 0..6 {
+        pool_exec!(pool, async move || {
+            utils::sleep(100).await;
+            Ok(JsValue::from(42))
+        });
+    }
+
+    sleep(50).await;
+    assert!(pool.count_threads() > 1);
+
+    sleep(1000).await;
+    assert_eq!(pool.count_pending_jobs(), 0);
+    assert_eq!(pool.count_threads(), 1);
+}
+
+#[wasm_bindgen_test]
+async fn default_size() {
+    let cores = utils::hardware_concurrency().unwrap();
+    assert_eq!(ThreadPool::default_size(None), (cores - 1).max(1));
+    assert_eq!(ThreadPool::default_size(Some(1)), 1);
+    assert_eq!(ThreadPool::default_size(Some(0)), 1);
+}
+
+#[wasm_bindgen_test]
+async fn queue_capacity() {
+    let pool = create_pool(2).await;
+    pool.set_queue_capacity(Some(1));
+
+    for i in 0..4 { // two running, one queued, one rejected
+        let result = pool.try_exec_async(FnOnce!(async move || {
+            utils::sleep(100).await;
+            Ok(JsValue::from(42))
+        }));
+        assert_eq!(result.is_ok(), i < 3);
+    }
+    assert!(pool.is_queue_full());
+
+    pool.exec_async_when_ready(FnOnce!(async move || {
+        utils::sleep(100).await;
+        Ok(JsValue::from(42))
+    })).await.unwrap();
+
+    sleep(500).await;
+    assert_eq!(pool.count_pending_jobs(), 0);
+    assert!(!pool.is_queue_full());
+}
+
+#[wasm_bindgen_test]
+async fn cancel() {
+    let pool = create_pool(1).await;
+
+    let running = pool_exec!(pool, async move || {
+        for _ in 0..100 {
+            utils::sleep(20).await;
+            utils::run_js("self.ticks = (self.ticks || 0) + 1;").unwrap();
+        }
+        Ok(JsValue::from(42))
+    });
+    let queued = pool_exec!(pool, move || Ok(JsValue::from(42)), move |result: ResultJJ| {
+        assert!(result.is_err());
+    });
+
+    sleep(50).await;
+    assert_eq!(running.status(), JobStatus::Running);
+    assert_eq!(queued.status(), JobStatus::Pending);
+    assert_eq!(pool.count_pending_jobs(), 1);
+
+    queued.cancel();
+    assert!(queued.is_canceled());
+    assert_eq!(pool.count_pending_jobs(), 0);
+
+    running.cancel();
+    sleep(100).await;
+    assert_eq!(pool.count_threads(), 1);
+
+    // Aborted on the same worker, so its ticks are still there but no more
+    let ticks = Rc::new(RefCell::new(Vec::new()));
+    for delay in &[0, 200] {
+        sleep(*delay).await;
+        let ticks = ticks.clone();
+        pool_exec_js!(pool, "return self.ticks;", move |result: ResultJJ| {
+            ticks.borrow_mut().push(result.unwrap().as_f64().unwrap());
+        });
+    }
+    sleep(100).await;
+    assert_eq!(ticks.borrow().len(), 2);
+    assert!(ticks.borrow()[0] > 0.0);
+    assert_eq!(ticks.borrow()[0], ticks.borrow()[1]);
+
+    let ok = pool_exec!(pool, move || Ok(JsValue::from(42)));
+    sleep(100).await;
+    assert_eq!(ok.status(), JobStatus::Done);
+}
+
+#[wasm_bindgen_test]
+async fn dead_worker() {
+    let pool = create_pool(1).await;
+    pool.set_dead_worker_policy(DeadWorkerPolicy::Fail);
+
+    let replaced = Rc::new(RefCell::new(0));
+    let count = replaced.clone();
+    pool.set_event_listener(move |event: &PoolEvent| {
+        if let PoolEvent::WorkerReplaced { .. } = event {
+            *count.borrow_mut() += 1;
+        }
+    });
+
+    pool_exec!(pool, move || -> ResultJJ { panic!("crash") }, move |result: ResultJJ| {
+        assert!(result.is_err());
+    });
+    sleep(1000).await;
+    assert_eq!(*replaced.borrow(), 1);
+    assert_eq!(pool.count_threads(), 1);
+
+    let ok = pool_exec!(pool, move || Ok(JsValue::from(42)));
+    sleep(100).await;
+    assert_eq!(ok.status(), JobStatus::Done);
+
+    // a worker dying while idle is replaced without waiting for the next job
+    pool_exec!(pool, move || utils::run_js("setTimeout(() => { throw new Error('late'); }, 50);"));
+    sleep(1000).await;
+    assert_eq!(*replaced.borrow(), 2);
+    assert_eq!(pool.count_threads(), 1);
+}
+
+#[wasm_bindgen_test]
+async fn stats() {
+    let pool = create_pool(2).await;
+
+    for i in 0..4 {
+        pool_exec!(pool, move || if i < 3 { Ok(JsValue::from(i)) } else { Err(JsValue::from(i)) });
+    }
+    sleep(500).await;
+
+    let stats = pool.stats();
+    assert_eq!(stats.threads.len(), 2);
+    assert_eq!(stats.pending_jobs, 0);
+    assert_eq!(stats.jobs_completed, 3);
+    assert_eq!(stats.jobs_failed, 1);
+    assert_eq!(stats.threads.iter().map(|ths| ths.jobs_completed + ths.jobs_failed).sum::<usize>(), 4);
+    assert!(stats.run_ms.max >= stats.run_ms.p50);
+}
+
+#[wasm_bindgen_test]
+async fn concurrency() {
+    let pool = create_pool(1).await;
+    pool.set_concurrency(4);
+
+    for _ in 0..4 {
+        pool_exec!(pool, async move || {
+            utils::sleep(200).await;
+            Ok(JsValue::from(42))
+        });
+    }
+    sleep(100).await;
+    assert_eq!(pool.count_pending_jobs(), 0);
+    assert_eq!(pool.stats().threads[0].in_flight, 4);
+
+    pool_exec!(pool, move || Ok(JsValue::from(42))); // waits for an exclusive thread
+    sleep(50).await;
+    assert_eq!(pool.count_pending_jobs(), 1);
+
+    sleep(500).await;
+    assert_eq!(pool.count_pending_jobs(), 0);
+}
+
+#[wasm_bindgen_test]
+async fn retry() {
+    let pool = create_pool(2).await;
+
+    let handle = pool_exec!(pool, move || Err(JsValue::from("flaky")), move |result: ResultJJ| {
+        assert_eq!(result, Err(JsValue::from("flaky"))); // as of the last attempt
+    }).with_retry(RetryPolicy::new(3).with_backoff(10, 2.0));
+    sleep(500).await;
+    assert_eq!(handle.attempts(), 3);
+
+    // retried on a different thread, even if it has to wait for one
+    pool_exec!(pool, async move || {
+        utils::sleep(200).await;
+        Ok(JsValue::NULL)
+    });
+    let handle = pool_exec!(pool, move || {
+        let n = utils::run_js("self.n = (self.n || 0) + 1; return self.n;")?;
+        if n.as_f64().unwrap() < 2.0 { Err(JsValue::from("first")) } else { Ok(n) }
+    }, move |result: ResultJJ| {
+        // the thread taken first being avoided
+        assert_eq!(result, Err(JsValue::from("first")));
+    }).with_retry(RetryPolicy::new(2));
+    sleep(500).await;
+    assert_eq!(handle.attempts(), 2);
+
+    let handle = pool_exec!(pool, move || Err(JsValue::from("fatal")))
+        .with_retry(RetryPolicy::new(3).retry_if(|err: &JsValue| err != &JsValue::from("fatal")));
+    sleep(200).await;
+    assert_eq!(handle.attempts(), 1);
+}
+
+#[wasm_bindgen_test]
+async fn task_graph() {
+    let pool = create_pool(2).await;
+
+    let mut graph = TaskGraph::new();
+    let a = graph.add(FnOnce!(move |_: Vec<JsValue>| Ok(JsValue::from(2))), &[]);
+    let b = graph.add_async(FnOnce!(async move |_: Vec<JsValue>| Ok(JsValue::from(3))), &[]);
+    let c = graph.add(FnOnce!(move |inputs: Vec<JsValue>| {
+        let product = inputs.iter().map(|jsv| jsv.as_f64().unwrap()).product::<f64>();
+        Ok(JsValue::from(product))
+    }), &[a, b]);
+    let d = graph.add(FnOnce!(move |_: Vec<JsValue>| Err(JsValue::from("oops"))), &[]);
+    let e = graph.add(FnOnce!(move |_: Vec<JsValue>| Ok(JsValue::from(42))), &[c, d]);
+
+    let results = pool.run_graph(graph).await;
+    assert_eq!(results.len(), 5);
+    assert_eq!(results[c.index()], Ok(JsValue::from(6)));
+    assert_eq!(results[d.index()], Err(JsValue::from("oops")));
+    assert!(results[e.index()].is_err());
+}
+
+#[wasm_bindgen_test]
+async fn par_iter() {
+    let pool = create_pool(2).await;
+
+    let doubled = pool.par_map((0..10).collect::<Vec<u32>>(), Fn!(|x: u32| x * 2)).await.unwrap();
+    assert_eq!(doubled, (0..10).map(|x| x * 2).collect::<Vec<u32>>());
+
+    let items: Vec<u32> = (1..=10).collect();
+    let sums = pool.par_chunks(&items, 3, Fn!(|chunk: Vec<u32>| chunk.iter().sum::<u32>())).await.unwrap();
+    assert_eq!(sums, vec![6, 15, 24, 10]);
+
+    let total = pool.par_reduce(items, 0, Fn!(|a: u32, b: u32| a + b)).await.unwrap();
+    assert_eq!(total, 55);
+}
+
+#[wasm_bindgen_test]
+async fn fork_join() {
+    let pool = create_pool(2).await;
+
+    let (a, b) = pool.join(
+        FnOnce!(move || Ok(JsValue::from(1))),
+        FnOnce!(move || Ok(JsValue::from(2)))).await;
+    assert_eq!((a.unwrap(), b.unwrap()), (JsValue::from(1), JsValue::from(2)));
+
+    let outputs = pool.scope(|s| async move {
+        for i in 0..4 {
+            s.spawn(FnOnce!(move || Ok(JsValue::from(i))));
+        }
+    }).await;
+    assert_eq!(outputs, Ok((0..4).map(JsValue::from).collect::<Vec<_>>()));
+
+    // nested tasks within a job are routed back to the pool
+    let (sum, _) = pool.join_async(
+        FnOnce!(async move || {
+            let (a, b) = wasm_mt_pool::join(
+                FnOnce!(move || Ok(JsValue::from(20))),
+                FnOnce!(move || Ok(JsValue::from(22)))).await;
+            Ok(JsValue::from(a?.as_f64().unwrap() + b?.as_f64().unwrap()))
+        }),
+        FnOnce!(async move || Ok(JsValue::NULL))).await;
+    assert_eq!(sum, Ok(JsValue::from(42)));
+
+    // other payloads are rejected by the pool
+    let (result, _) = pool.join_async(
+        FnOnce!(async move || wasm_mt::call_main(&JsValue::from("x"), None).await),
+        FnOnce!(async move || Ok(JsValue::NULL))).await;
+    assert_eq!(result, Err(JsValue::from("ThreadPool: unexpected call payload")));
+
+    // outside of jobs, nested tasks run inline
+    let (a, b) = wasm_mt_pool::join(
+        FnOnce!(move || Ok(JsValue::from(1))),
+        FnOnce!(move || Ok(JsValue::from(2)))).await;
+    assert_eq!((a.unwrap(), b.unwrap()), (JsValue::from(1), JsValue::from(2)));
+    let outputs = wasm_mt_pool::scope(|s| async move {
+        s.spawn(FnOnce!(move || Ok(JsValue::from(0))));
+    }).await;
+    assert_eq!(outputs, Ok(vec![JsValue::from(0)]));
+}
+
+#[wasm_bindgen_test]
+async fn scope() {
+    let pool = create_pool(1).await;
+
+    let result = pool.scope(|s| async move {
+        s.spawn(FnOnce!(move || Err(JsValue::from("oops"))));
+        s.spawn(FnOnce!(move || Ok(JsValue::from(42)))); // canceled while pending
+        sleep(100).await;
+        s.spawn(FnOnce!(move || Ok(JsValue::from(42)))); // canceled on spawn
+    }).await;
+    assert_eq!(result, Err(JsValue::from("oops")));
+    assert_eq!(pool.count_pending_jobs(), 0);
+    assert_eq!(pool.stats().jobs_canceled, 2);
+}
+
+#[wasm_bindgen_test]
+async fn race() {
+    let pool = create_pool(3).await;
+
+    let jobs = (0..3).map(|i: u32| RaceJob::new(FnOnce!(move || {
+        if i == 0 { Err(JsValue::from("oops")) } else { Ok(JsValue::from(i)) }
+    }))).collect();
+    let result = pool.race(jobs).await;
+    assert!(result == Ok(JsValue::from(1)) || result == Ok(JsValue::from(2)));
+
+    let jobs = (0..2).map(|_| RaceJob::new(FnOnce!(move || Err(JsValue::from("oops"))))).collect();
+    assert_eq!(pool.race(jobs).await, Err(JsValue::from("oops")));
+
+    // Closures of different types; the loser is aborted on its worker
+    let result = pool.race(vec![
+        RaceJob::new(FnOnce!(move || Ok(JsValue::from("fast")))),
+        RaceJob::new_async(FnOnce!(async move || {
+            utils::sleep(200).await;
+            utils::run_js("self.lost = true;").unwrap();
+            Ok(JsValue::from("slow"))
+        })),
+    ]).await;
+    assert_eq!(result, Ok(JsValue::from("fast")));
+    sleep(300).await;
+    let lost = pool.broadcast(FnOnce!(move || utils::run_js("return !!self.lost;"))).await;
+    assert!(lost.iter().all(|result| *result == Ok(JsValue::FALSE)));
+
+    let result = pool.hedge(RaceJob::new_async(FnOnce!(async move || {
+        utils::sleep(200).await;
+        Ok(JsValue::from(42))
+    })), 50).await;
+    assert_eq!(result, Ok(JsValue::from(42)));
+
+    // No duplicate on the job's own thread, even with room for it
+    let pool = create_pool(1).await;
+    pool.set_concurrency(2);
+    let result = pool.hedge(RaceJob::new_async(FnOnce!(async move || {
+        utils::run_js("self.hedged = (self.hedged || 0) + 1;").unwrap();
+        utils::sleep(200).await;
+        Ok(JsValue::from(42))
+    })), 50).await;
+    assert_eq!(result, Ok(JsValue::from(42)));
+    let hedged = pool.broadcast(FnOnce!(move || utils::run_js("return self.hedged;"))).await;
+    assert_eq!(hedged, vec![Ok(JsValue::from(1))]);
+}
+
+#[wasm_bindgen_test]
+async fn prefetch_and_batching() {
+    let pool = create_pool(2).await;
+
+    pool.set_prefetch(2);
+    for _ in 0..6 {
+        pool_exec!(pool, move || Ok(JsValue::from(42)));
+    }
+    sleep(10).await;
+    assert_eq!(pool.count_pending_jobs(), 0); // all sent to the workers at once
+    sleep(200).await;
+    assert_eq!(pool.stats().jobs_completed, 6);
+
+    pool.set_prefetch(0);
+    pool.set_batching(Some(4));
+    let results = Rc::new(RefCell::new(Vec::new()));
+    for i in 0..6 {
+        let results = results.clone();
+        pool_exec!(pool, move || Ok(JsValue::from(i)), move |result: ResultJJ| {
+            results.borrow_mut().push(result.unwrap());
+        });
+    }
+    sleep(200).await;
+    assert_eq!(results.borrow().len(), 6);
+    assert_eq!(pool.stats().jobs_completed, 6 + 6); // counted per member
+
+    // Once sent, a member can no longer be canceled
+    let sent = pool_exec!(pool, move || {
+        utils::run_js("const t = Date.now(); while (Date.now() - t < 100) {}")
+    });
+    sleep(30).await;
+    sent.cancel();
+    assert_eq!(sent.status(), JobStatus::Running);
+    sleep(200).await;
+    assert_eq!(sent.status(), JobStatus::Done);
+
+    // Retried on its own, avoiding the thread of the previous attempt
+    let retried = pool_exec!(pool, move || {
+        let n = utils::run_js("self.n = (self.n || 0) + 1; return self.n;")?;
+        if n.as_f64().unwrap() < 2.0 { Err(JsValue::from("not yet")) } else { Ok(n) }
+    }).with_retry(RetryPolicy::new(3));
+    sleep(300).await;
+    assert_eq!(retried.status(), JobStatus::Done);
+    assert_eq!(retried.attempts(), 3);
+}
+
+#[wasm_bindgen_test]
+async fn broadcast() {
+    let pool = create_pool(3).await;
+
+    pool_exec!(pool, async move || {
+        utils::sleep(100).await;
+        Ok(JsValue::NULL)
+    }); // keeps a thread busy for a while
+
+    let results = pool.broadcast(FnOnce!(move || Ok(JsValue::from(42)))).await;
+    assert_eq!(results, vec![Ok(JsValue::from(42)); 3]);
+    let stats = pool.stats();
+    assert!(stats.threads.iter().all(|ths| ths.jobs_completed >= 1));
+}
+
+#[wasm_bindgen_test]
+async fn worker_init() {
+    let pool = new_pool(2).await
+        .with_worker_init(FnOnce!(move || {
+            js_sys::Reflect::set(&js_sys::global(), &JsValue::from("wmtInit"), &JsValue::from(42))?;
+            Ok(JsValue::NULL)
+        }))
+        .and_init().await.unwrap();
+
+    let results = pool.broadcast(FnOnce!(move || {
+        js_sys::Reflect::get(&js_sys::global(), &JsValue::from("wmtInit"))
+    })).await;
+    assert_eq!(results, vec![Ok(JsValue::from(42)); 2]);
+
+    let pool = new_pool(2).await
+        .with_worker_init(FnOnce!(move || Err(JsValue::from("oops"))));
+    let err = pool.init().await.err().unwrap();
+    let failed = js_sys::Array::from(&Reflect::get(&err, &JsValue::from("failed")).unwrap());
+    assert_eq!(failed.length(), 2); // each thread, with the hook's error
+    failed.for_each(&mut |entry, _, _| {
+        assert_eq!(js_sys::Array::from(&entry).get(1), JsValue::from("oops"));
+    });
+}
+
+#[wasm_bindgen_test]
+async fn lazy_spawn() {
+    let pool = new_pool(3).await;
+    pool.set_lazy_spawn(Some(1));
+    pool.init().await.unwrap();
+    assert_eq!(pool.count_threads(), 1);
+
+    for _ in 0..2 {
+        pool_exec!(pool, async move || {
+            utils::sleep(200).await;
+            Ok(JsValue::NULL)
+        });
+    }
+    sleep(1000).await;
+    assert_eq!(pool.count_threads(), 3); // grown on demand, plus a standby one within the size
+    assert_eq!(pool.stats().jobs_completed, 2);
+}
+
+#[wasm_bindgen_test]
+async fn idle_timeout() {
+    let pool = create_pool(2).await;
+    pool.set_idle_timeout(Some(200));
+
+    sleep(600).await;
+    assert_eq!(pool.count_threads(), 0);
+
+    let result = Rc::new(RefCell::new(None));
+    let result_cb = result.clone();
+    pool_exec!(pool, move || Ok(JsValue::from(42)), move |res: ResultJJ| {
+        result_cb.replace(Some(res));
+    });
+    sleep(500).await;
+    assert_eq!(*result.borrow(), Some(Ok(JsValue::from(42))));
+    assert_eq!(pool.count_threads(), 1); // respawned on demand
+}
+
+#[wasm_bindgen_test]
+async fn recycling() {
+    let pool = create_pool(1).await;
+    pool.set_recycling(Some(2), None);
+
+    let reasons = Rc::new(RefCell::new(Vec::new()));
+    let reasons_cb = reasons.clone();
+    pool.set_event_listener(move |event: &PoolEvent| {
+        if let PoolEvent::WorkerReplaced { reason, .. } = event {
+            reasons_cb.borrow_mut().push(reason.clone());
+        }
+    });
+
+    for i in 0..4 {
+        pool_exec!(pool, move || Ok(JsValue::from(i)));
+    }
+    sleep(2000).await;
+    assert_eq!(*reasons.borrow(), vec!["recycled", "recycled"]);
+    assert_eq!(pool.count_threads(), 1);
+    assert_eq!(pool.count_pending_jobs(), 0);
+}
+
+#[wasm_bindgen_test]
+async fn recycling_by_memory() {
+    let pool = create_pool(1).await;
+    // Any worker is over this, sampled as of its first job
+    pool.set_recycling(None, Some(1.0));
+
+    let reasons = Rc::new(RefCell::new(Vec::new()));
+    let reasons_cb = reasons.clone();
+    pool.set_event_listener(move |event: &PoolEvent| {
+        if let PoolEvent::WorkerReplaced { reason, .. } = event {
+            reasons_cb.borrow_mut().push(reason.clone());
+        }
+    });
+
+    pool_exec!(pool, move || Ok(JsValue::NULL));
+    sleep(2000).await;
+    assert_eq!(*reasons.borrow(), vec!["recycled"]);
+    assert_eq!(pool.count_threads(), 1);
+}
@@ -16,11 +16,9 @@ pub fn app() {
 
 async fn run_executors(mt: WasmMt) -> Result<(), JsValue> {
     // Prepare threads
-    let mut v: Vec<wasm_mt::Thread> = vec![];
-    for i in 0..4 {
-        let th = mt.thread().and_init().await?;
+    let v: Vec<wasm_mt::Thread> = mt.threads(4).await?;
+    for (i, th) in v.iter().enumerate() {
         th.set_id(&i.to_string());
-        v.push(th);
     }
 
     console_ln!("🔥 serial executor:");
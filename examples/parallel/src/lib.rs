@@ -137,11 +137,9 @@ pub async fn run(mt: WasmMt) -> Result<(), JsValue> {
 
     // Prepare threads
 
-    let mut v: Vec<wasm_mt::Thread> = vec![];
-    for i in 0..num {
-        let th = mt.thread().and_init().await?;
+    let v: Vec<wasm_mt::Thread> = mt.threads(num).await?;
+    for (i, th) in v.iter().enumerate() {
         th.set_id(&i.to_string());
-        v.push(th);
     }
 
     // Serial executor
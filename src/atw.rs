@@ -3,13 +3,20 @@
 use crate::{debug_ln, console_ln};
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
-use wasm_bindgen_futures::JsFuture;
+use wasm_bindgen_futures::{spawn_local, JsFuture};
 use js_sys::{Array, Function, Object, Promise, Reflect};
 use web_sys::{MessageEvent, Worker, WorkerGlobalScope};
 use uuid::Uuid;
 use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
 use std::rc::Rc;
-use std::cell::{Ref, RefCell, RefMut};
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::task::{Context, Poll, Waker};
+use super::sync_call;
+use super::utils::sleep;
+
+const CLOSE_GRACE_MS: u32 = 1_000;
 
 fn atw_encode_req_msg(id: &Uuid, payload: &JsValue) -> Object {
     let msg = Object::new();
@@ -46,12 +53,119 @@ fn atw_decode_result_msg(msg: &JsValue) -> (Uuid, JsValue, bool) {
     (id, result, is_ok)
 }
 
+// Calls are requests the other way around, i.e. from a worker to the main thread
+
+fn atw_encode_call_msg(id: &Uuid, payload: &JsValue) -> Object {
+    let msg = Object::new();
+    Reflect::set(msg.as_ref(), &JsValue::from("call"), &JsValue::from(&id.to_string())).unwrap();
+    Reflect::set(msg.as_ref(), &JsValue::from("payload"), payload).unwrap();
+    msg
+}
+
+fn atw_decode_call_msg(msg: &JsValue) -> Option<(String, JsValue)> {
+    if !Reflect::has(msg, &JsValue::from("call")).unwrap_or(false) {
+        return None;
+    }
+    let id = Reflect::get(msg, &JsValue::from("call"))
+        .unwrap_throw().as_string().unwrap_throw();
+    let payload = Reflect::get(msg, &JsValue::from("payload"))
+        .unwrap_throw();
+    Some((id, payload))
+}
+
+fn atw_encode_call_result_msg(id: &str, result: &JsValue, is_ok: bool) -> Object {
+    let msg = Object::new();
+    Reflect::set(msg.as_ref(), &JsValue::from("callId"), &JsValue::from(id)).unwrap();
+    Reflect::set(msg.as_ref(), &JsValue::from("result"), result).unwrap();
+    Reflect::set(msg.as_ref(), &JsValue::from("isOk"), &JsValue::from(is_ok)).unwrap();
+    msg
+}
+
+fn atw_decode_call_result_msg(msg: &JsValue) -> Option<(Uuid, JsValue, bool)> {
+    if !Reflect::has(msg, &JsValue::from("callId")).unwrap_or(false) {
+        return None;
+    }
+    let id = Reflect::get(msg, &JsValue::from("callId"))
+        .unwrap_throw().as_string().unwrap_throw();
+    let id = Uuid::parse_str(&id).unwrap_throw();
+
+    let result = Reflect::get(msg, &JsValue::from("result"))
+        .unwrap_throw();
+    let is_ok = Reflect::get(msg, &JsValue::from("isOk"))
+        .unwrap_throw().as_bool().unwrap_throw();
+    Some((id, result, is_ok))
+}
+
+// Sync calls are calls the main thread forwards to another worker, with the
+// caller blocking until the result is written to a shared slot
+
+fn atw_encode_sync_call_msg(id: &Uuid, payload: &JsValue) -> Object {
+    let msg = Object::new();
+    Reflect::set(msg.as_ref(), &JsValue::from("syncCall"), &JsValue::from(&id.to_string())).unwrap();
+    Reflect::set(msg.as_ref(), &JsValue::from("payload"), payload).unwrap();
+    msg
+}
+
+fn atw_decode_sync_call_msg(msg: &JsValue) -> Option<(String, JsValue)> {
+    if !Reflect::has(msg, &JsValue::from("syncCall")).unwrap_or(false) {
+        return None;
+    }
+    let id = Reflect::get(msg, &JsValue::from("syncCall"))
+        .unwrap_throw().as_string().unwrap_throw();
+    let payload = Reflect::get(msg, &JsValue::from("payload"))
+        .unwrap_throw();
+    Some((id, payload))
+}
+
+fn atw_encode_sync_call_timeout_msg(id: &Uuid) -> Object {
+    let msg = Object::new();
+    Reflect::set(msg.as_ref(), &JsValue::from("syncCallTimeout"), &JsValue::from(&id.to_string())).unwrap();
+    msg
+}
+
+fn atw_decode_sync_call_timeout_msg(msg: &JsValue) -> Option<String> {
+    if !Reflect::has(msg, &JsValue::from("syncCallTimeout")).unwrap_or(false) {
+        return None;
+    }
+    Reflect::get(msg, &JsValue::from("syncCallTimeout"))
+        .unwrap_throw().as_string()
+}
+
 // Bindings such as `post_message_with_transfer()` seem not available
 // in `web_sys::WorkerGlobalScope` (as opposed to `web_sys::Worker`).
 // So, we define and use a custom binding `JsWgs` instead.
 
 pub struct ThreadWorker {
     wgs: JsWgs,
+    calls: RefCell<RrMap>,
+    locals: RefCell<HashMap<String, (Function, Function)>>,
+    runs: RefCell<HashMap<String, Rc<Run>>>,
+}
+
+// An async request in flight on the worker
+struct Run {
+    can_abort: bool,
+    is_aborted: Cell<bool>,
+    waker: RefCell<Option<Waker>>,
+}
+
+// Resolves to an error once aborted, dropping the inner future at the point
+// it is awaiting
+pub struct Tracked<F> {
+    fut: Pin<Box<F>>,
+    run: Rc<Run>,
+}
+
+impl<F> Future for Tracked<F> where F: Future<Output = Result<JsValue, JsValue>> {
+    type Output = Result<JsValue, JsValue>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        if self.run.is_aborted.get() {
+            return Poll::Ready(Err(JsValue::from("Thread: run aborted")));
+        }
+        self.run.waker.replace(Some(cx.waker().clone()));
+        self.fut.as_mut().poll(cx)
+    }
 }
 
 #[wasm_bindgen]
@@ -73,11 +187,72 @@ impl ThreadWorker {
     pub fn new(wgs: WorkerGlobalScope) -> Self {
         Self {
             wgs: JsWgs::new(wgs),
+            calls: RefCell::new(HashMap::new()),
+            locals: RefCell::new(HashMap::new()),
+            runs: RefCell::new(HashMap::new()),
+        }
+    }
+
+    // Track an async request until it responds, so that `abort()` can stop
+    // it; `can_abort` is `false` for futures that keep going regardless,
+    // e.g. of JS promises
+    pub fn track<F>(&self, req_id: &str, fut: F, can_abort: bool) -> Tracked<F> where F: Future<Output = Result<JsValue, JsValue>> {
+        let run = Rc::new(Run {
+            can_abort,
+            is_aborted: Cell::new(false),
+            waker: RefCell::new(None),
+        });
+        self.runs.borrow_mut().insert(req_id.to_string(), run.clone());
+        Tracked { fut: Box::pin(fut), run }
+    }
+
+    // Returns `false` if the request is still running and cannot be aborted,
+    // e.g. as it is not async; a request already done counts as aborted
+    pub fn abort(&self, req_id: &str) -> bool {
+        let run = self.runs.borrow().get(req_id).cloned();
+        match run {
+            Some(run) if run.can_abort => {
+                run.is_aborted.set(true);
+                if let Some(waker) = run.waker.replace(None) {
+                    waker.wake();
+                }
+                true
+            },
+            Some(_) => false,
+            None => true,
+        }
+    }
+
+    // Run a request within the worker itself, e.g. a member of a batch, so
+    // that its result is captured instead of being posted
+    pub async fn run_local<F>(&self, req_id: &str, run: F) -> Result<JsValue, JsValue> where F: FnOnce(&str) {
+        let promise = Promise::new(&mut |res, rej| {
+            self.locals.borrow_mut().insert(req_id.to_string(), (res, rej));
+        });
+        run(req_id);
+
+        JsFuture::from(promise).await
+    }
+
+    fn settle_local(&self, req_id: &str, result: &JsValue, is_ok: bool) -> bool {
+        let local = self.locals.borrow_mut().remove(req_id);
+        match local {
+            Some((res, rej)) => {
+                (if is_ok { res } else { rej })
+                    .call1(&JsValue::NULL, result)
+                    .unwrap_throw();
+                true
+            },
+            None => false,
         }
     }
 
     pub fn send_response(&self, req_id: &str, payload: &JsValue, transfer: Option<&Array>) {
         debug_ln!("send_response(): req_id: {} payload: {:?} transfer: {:?}", req_id, payload, transfer);
+        self.runs.borrow_mut().remove(req_id);
+        if self.settle_local(req_id, payload, true) {
+            return;
+        }
 
         let default = Array::new();
         let transfer = transfer.unwrap_or(&default);
@@ -87,11 +262,57 @@ impl ThreadWorker {
 
     pub fn send_error(&self, req_id: &str, error: &JsValue) {
         debug_ln!("send_error(): req_id: {} error: {:?}", req_id, error);
+        self.runs.borrow_mut().remove(req_id);
+        if self.settle_local(req_id, error, false) {
+            return;
+        }
 
         self.wgs.post_message_with_transfer(
             &atw_encode_result_msg(req_id, error, false), &Array::new());
     }
 
+    pub async fn call(&self, payload: &JsValue, transfer: Option<&Array>) -> Result<JsValue, JsValue> {
+        let promise = Promise::new(&mut |res, rej| {
+            let call_id = Thread::new_req_id(self.calls.borrow());
+            self.calls.borrow_mut().insert(call_id, (res, rej));
+
+            let default = Array::new();
+            let transfer = transfer.unwrap_or(&default);
+            self.wgs.post_message_with_transfer(
+                &atw_encode_call_msg(&call_id, payload), transfer);
+        });
+
+        JsFuture::from(promise).await
+    }
+
+    // Returns `false` if `msg` is not the result of a call
+    pub fn on_call_result(&self, msg: &JsValue) -> bool {
+        match atw_decode_call_result_msg(msg) {
+            Some((id, result, is_ok)) => {
+                let (res, rej) = self.calls.borrow_mut().remove(&id).unwrap_throw();
+                (if is_ok { res } else { rej })
+                    .call1(&JsValue::NULL, &result)
+                    .unwrap_throw();
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Post a sync call, which the main thread does not respond to as the
+    // caller is to block until the result is written elsewhere
+    pub fn post_sync_call(&self, call_id: &Uuid, payload: &JsValue, transfer: Option<&Array>) {
+        let default = Array::new();
+        self.wgs.post_message_with_transfer(
+            &atw_encode_sync_call_msg(call_id, payload), transfer.unwrap_or(&default));
+    }
+
+    // Let the main thread know that the caller has stopped waiting
+    pub fn post_sync_call_timeout(&self, call_id: &Uuid) {
+        self.wgs.post_message_with_transfer(
+            &atw_encode_sync_call_timeout_msg(call_id), &Array::new());
+    }
+
     pub fn set_callback_of(&self, target: &str, cb: &JsValue) {
         // debug_ln!("set_callback_of(): target: {}", target);
         Reflect::set(&self.wgs, &JsValue::from(target),
@@ -100,6 +321,8 @@ impl ThreadWorker {
 }
 
 type RrMap = HashMap<Uuid, (Function, Function)>;
+type CallHandler = Rc<dyn Fn(JsValue) -> Promise>;
+type ErrorHandler = Rc<dyn Fn()>;
 
 pub struct Thread {
     worker: Worker,
@@ -107,6 +330,9 @@ pub struct Thread {
     _on_error: Box<Closure<dyn FnMut(MessageEvent)>>,
     rr_map: Rc<RefCell<RrMap>>,
     is_terminated: RefCell<bool>,
+    has_error: Rc<RefCell<bool>>,
+    call_handler: Rc<RefCell<Option<CallHandler>>>,
+    error_handler: Rc<RefCell<Option<ErrorHandler>>>,
 }
 
 impl Thread {
@@ -123,9 +349,12 @@ impl Thread {
         let worker = worker.unwrap_throw();
 
         let rr_map = Rc::new(RefCell::new(HashMap::new()));
-        let on_message = Self::create_onmessage(rr_map.clone());
+        let call_handler = Rc::new(RefCell::new(None));
+        let on_message = Self::create_onmessage(rr_map.clone(), worker.clone(), call_handler.clone());
         worker.set_onmessage(Some(on_message.as_ref().unchecked_ref::<Function>()));
-        let on_error = Self::create_onerror(rr_map.clone());
+        let has_error = Rc::new(RefCell::new(false));
+        let error_handler = Rc::new(RefCell::new(None));
+        let on_error = Self::create_onerror(rr_map.clone(), has_error.clone(), error_handler.clone());
         worker.set_onerror(Some(on_error.as_ref().unchecked_ref::<Function>()));
 
         Self {
@@ -134,10 +363,13 @@ impl Thread {
             _on_message: Box::new(on_message),
             _on_error: Box::new(on_error),
             is_terminated: RefCell::new(false),
+            has_error,
+            call_handler,
+            error_handler,
         }
     }
 
-    fn create_onmessage(rr_map: Rc<RefCell<RrMap>>) -> Closure<dyn FnMut(MessageEvent)> {
+    fn create_onmessage(rr_map: Rc<RefCell<RrMap>>, worker: Worker, call_handler: Rc<RefCell<Option<CallHandler>>>) -> Closure<dyn FnMut(MessageEvent)> {
         Closure::wrap(Box::new(move |me: MessageEvent| {
             let msg = me.data();
 
@@ -147,6 +379,20 @@ impl Thread {
                 return;
             }
 
+            if let Some((call_id, payload)) = atw_decode_sync_call_msg(&msg) {
+                sync_call::serve(&worker, &call_id, &payload);
+                return;
+            }
+            if let Some(call_id) = atw_decode_sync_call_timeout_msg(&msg) {
+                sync_call::forget(&call_id);
+                return;
+            }
+
+            if let Some((call_id, payload)) = atw_decode_call_msg(&msg) {
+                Self::on_call(&worker, &call_handler, call_id, &payload);
+                return;
+            }
+
             let (id, result, is_ok) = atw_decode_result_msg(&msg);
 
             let mut rr_map = rr_map.borrow_mut();
@@ -160,9 +406,41 @@ impl Thread {
         }) as Box<dyn FnMut(MessageEvent)>)
     }
 
-    fn create_onerror(rr_map: Rc<RefCell<RrMap>>) -> Closure<dyn FnMut(MessageEvent)> {
+    fn on_call(worker: &Worker, call_handler: &RefCell<Option<CallHandler>>, call_id: String, payload: &JsValue) {
+        let handler = call_handler.borrow().clone();
+        let promise = match handler {
+            Some(handler) => handler(payload.clone()),
+            None => Promise::reject(&JsValue::from("Thread: no call handler")),
+        };
+
+        let worker = worker.clone();
+        spawn_local(async move {
+            let (result, is_ok) = match JsFuture::from(promise).await {
+                Ok(jsv) => (jsv, true),
+                Err(jsv) => (jsv, false),
+            };
+            // fails if the worker has been terminated meanwhile
+            let _ = worker.post_message(&atw_encode_call_result_msg(&call_id, &result, is_ok));
+        });
+    }
+
+    pub fn set_call_handler(&self, handler: CallHandler) {
+        self.call_handler.replace(Some(handler));
+    }
+
+    pub fn set_error_handler(&self, handler: ErrorHandler) {
+        self.error_handler.replace(Some(handler));
+    }
+
+    fn create_onerror(rr_map: Rc<RefCell<RrMap>>, has_error: Rc<RefCell<bool>>, error_handler: Rc<RefCell<Option<ErrorHandler>>>) -> Closure<dyn FnMut(MessageEvent)> {
         Closure::wrap(Box::new(move |_me: MessageEvent| {
+            has_error.replace(true);
             Self::cancel_pending_requests(rr_map.borrow_mut());
+
+            let handler = error_handler.borrow().clone();
+            if let Some(handler) = handler {
+                handler();
+            }
         }) as Box<dyn FnMut(MessageEvent)>)
     }
 
@@ -183,13 +461,24 @@ impl Thread {
     }
 
     pub async fn send_request(&self, payload: &JsValue, transfer: Option<&Array>) -> Result<JsValue, JsValue> {
+        let req_id = Self::new_req_id(self.rr_map.borrow());
+        self.send_request_as(&req_id, payload, transfer).await
+    }
+
+    // Like `send_request()` but with an id given by `new_run_id()`, e.g. to
+    // refer to the request later on
+    pub async fn send_request_as(&self, req_id: &Uuid, payload: &JsValue, transfer: Option<&Array>) -> Result<JsValue, JsValue> {
         let promise = Promise::new(&mut |res, rej| {
             if *self.is_terminated.borrow() {
                 rej.call1(&JsValue::NULL, &JsValue::from("worker already terminated")).unwrap_throw();
                 return;
             }
+            if self.rr_map.borrow().get(req_id).is_some() {
+                rej.call1(&JsValue::NULL, &JsValue::from("Thread: req id in use")).unwrap_throw();
+                return;
+            }
 
-            let req_id = Self::new_req_id(self.rr_map.borrow());
+            let req_id = *req_id;
             self.rr_map.borrow_mut().insert(req_id, (res, rej));
 
             let default = Array::new();
@@ -201,6 +490,10 @@ impl Thread {
         JsFuture::from(promise).await
     }
 
+    pub fn new_run_id(&self) -> Uuid {
+        Self::new_req_id(self.rr_map.borrow())
+    }
+
     fn cancel_pending_requests(mut rr_map: RefMut<RrMap>) {
         let cancels = rr_map.len();
         debug_ln!("cancel_pending_requests(): canceling {} pending reqs", cancels);
@@ -221,9 +514,39 @@ impl Thread {
         }
     }
 
+    // Have the worker close itself upon `payload`, a request it does not
+    // respond to; a worker with requests in flight, or not closed within
+    // `CLOSE_GRACE_MS`, gets terminated instead.
+    pub fn close_with(&self, payload: &JsValue) {
+        if *self.is_terminated.borrow() || !self.rr_map.borrow().is_empty() {
+            self.terminate();
+            return;
+        }
+
+        self.is_terminated.replace(true);
+        let req_id = Self::new_req_id(self.rr_map.borrow());
+        // fails if the worker has died meanwhile
+        let _ = self.worker.post_message(&atw_encode_req_msg(&req_id, payload));
+
+        // In case the worker never gets to close itself, e.g. stuck in a job
+        let worker = self.worker.clone();
+        spawn_local(async move {
+            sleep(CLOSE_GRACE_MS).await;
+            worker.terminate();
+        });
+    }
+
     pub fn is_terminated(&self) -> bool {
         *self.is_terminated.borrow()
     }
+
+    pub fn worker(&self) -> &Worker {
+        &self.worker
+    }
+
+    pub fn has_error(&self) -> bool {
+        *self.has_error.borrow()
+    }
 }
 
 impl Drop for Thread {
@@ -1,10 +1,11 @@
 use std::future::Future;
+use std::pin::Pin;
 use std::rc::Rc;
 use std::marker::PhantomData;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen::JsCast;
 use wasm_bindgen_futures::spawn_local;
-use js_sys::{ArrayBuffer, Uint8Array};
+use js_sys::{Array, ArrayBuffer, Uint8Array};
 use serde::{de::DeserializeOwned, Serialize};
 use serde_closure::FnOnce;
 use serde_traitobject;
@@ -16,6 +17,9 @@ type ResultJJ = Result<JsValue, JsValue>;
 pub trait MtClosure = FnOnce() -> ResultJJ + Serialize + DeserializeOwned + 'static;
 pub trait MtAsyncClosure<T> = FnOnce() -> T + Serialize + DeserializeOwned + 'static
     where T: Future<Output = ResultJJ> + 'static;
+pub trait MtClosureArgs = FnOnce(Vec<JsValue>) -> ResultJJ + Serialize + DeserializeOwned + 'static;
+pub trait MtAsyncClosureArgs<T> = FnOnce(Vec<JsValue>) -> T + Serialize + DeserializeOwned + 'static
+    where T: Future<Output = ResultJJ> + 'static;
 
 fn send_result(result: ResultJJ, atw_thw: Rc<AtwThreadWorker>, req_id: &str) {
     match result {
@@ -25,20 +29,50 @@ fn send_result(result: ResultJJ, atw_thw: Rc<AtwThreadWorker>, req_id: &str) {
     }
 }
 
+// Byte length of the worker's wasm memory, which grows but never shrinks
+pub fn run_memory_size(atw_thw: Rc<AtwThreadWorker>, req_id: &str) {
+    let buffer = wasm_bindgen::memory().unchecked_into::<js_sys::WebAssembly::Memory>().buffer();
+    send_result(js_sys::Reflect::get(&buffer, &JsValue::from("byteLength")), atw_thw, req_id);
+}
+
+// Type-erased jobs, e.g. for handing over to `Thread::exec_ab()` by another thread
+pub fn clos_to_ab<F>(clos: F) -> ArrayBuffer where F: MtClosure {
+    Job::<Pin<Box<dyn Future<Output = ResultJJ>>>>::from_clos(clos)
+}
+
+pub fn aclos_to_ab<F, T>(aclos: F) -> ArrayBuffer where F: MtAsyncClosure<T> {
+    Job::<T>::from_aclos(aclos)
+}
+
+pub fn clos_args_to_ab<F>(clos: F) -> ArrayBuffer where F: MtClosureArgs {
+    Job::<Pin<Box<dyn Future<Output = ResultJJ>>>>::from_clos_args(clos)
+}
+
+pub fn aclos_args_to_ab<F, T>(aclos: F) -> ArrayBuffer where F: MtAsyncClosureArgs<T> {
+    Job::<T>::from_aclos_args(aclos)
+}
+
+fn args_to_vec(args: &JsValue) -> Vec<JsValue> {
+    args.dyn_ref::<Array>().unwrap().iter().collect()
+}
+
 pub fn run_job_js(jsv: &JsValue, atw_thw: Rc<AtwThreadWorker>, req_id: &str, is_async: bool) {
     let js = jsv.as_string().unwrap();
     if is_async {
         let req_id = req_id.to_string();
         spawn_local(async move {
-            send_result(utils::run_js_async(js.as_str()).await, atw_thw, &req_id);
+            // The promise keeps going even if the future gets dropped
+            let result = atw_thw.track(&req_id, utils::run_js_async(js.as_str()), false).await;
+            send_result(result, atw_thw, &req_id);
         });
     } else {
         send_result(utils::run_js(js.as_str()), atw_thw, req_id);
     }
 }
 
+// The `JsValue` passed to `clos_fold` holds the args of `*_args` closures
 pub struct Job<T> {
-    clos_fold: Box<dyn serde_traitobject::FnOnce<(Rc<AtwThreadWorker>, String,), Output = ()> + 'static>,
+    clos_fold: Box<dyn serde_traitobject::FnOnce<(Rc<AtwThreadWorker>, String, JsValue,), Output = ()> + 'static>,
     _phantom: PhantomData<T>,
 }
 
@@ -47,7 +81,7 @@ impl<T> Job<T> where T: Future<Output = ResultJJ> + 'static {
         let vec: Vec<u8> = bincode::serialize(&clos).unwrap();
         { #[allow(warnings)] {
             (Self {
-                clos_fold: Box::new(FnOnce!(move |atw_thw: Rc<AtwThreadWorker>, req_id: String| {
+                clos_fold: Box::new(FnOnce!(move |atw_thw: Rc<AtwThreadWorker>, req_id: String, _args: JsValue| {
                     let clos: F = bincode::deserialize(&vec).unwrap();
                     send_result(clos(), atw_thw, &req_id);
                 })),
@@ -59,10 +93,38 @@ impl<T> Job<T> where T: Future<Output = ResultJJ> + 'static {
         let vec: Vec<u8> = bincode::serialize(&clos).unwrap();
         { #[allow(warnings)] {
             (Self {
-                clos_fold: Box::new(FnOnce!(move |atw_thw: Rc<AtwThreadWorker>, req_id: String| {
+                clos_fold: Box::new(FnOnce!(move |atw_thw: Rc<AtwThreadWorker>, req_id: String, _args: JsValue| {
+                    spawn_local(async move {
+                        let clos: F = bincode::deserialize(&vec).unwrap();
+                        let result = atw_thw.track(&req_id, clos(), true).await;
+                        send_result(result, atw_thw, &req_id);
+                    });
+                })),
+                _phantom: PhantomData,
+            }).to_ab()
+        } }
+    }
+    pub fn from_clos_args<F>(clos: F) -> ArrayBuffer where F: MtClosureArgs {
+        let vec: Vec<u8> = bincode::serialize(&clos).unwrap();
+        { #[allow(warnings)] {
+            (Self {
+                clos_fold: Box::new(FnOnce!(move |atw_thw: Rc<AtwThreadWorker>, req_id: String, args: JsValue| {
+                    let clos: F = bincode::deserialize(&vec).unwrap();
+                    send_result(clos(args_to_vec(&args)), atw_thw, &req_id);
+                })),
+                _phantom: PhantomData,
+            }).to_ab()
+        } }
+    }
+    pub fn from_aclos_args<F>(clos: F) -> ArrayBuffer where F: MtAsyncClosureArgs<T> {
+        let vec: Vec<u8> = bincode::serialize(&clos).unwrap();
+        { #[allow(warnings)] {
+            (Self {
+                clos_fold: Box::new(FnOnce!(move |atw_thw: Rc<AtwThreadWorker>, req_id: String, args: JsValue| {
                     spawn_local(async move {
                         let clos: F = bincode::deserialize(&vec).unwrap();
-                        send_result(clos().await, atw_thw, &req_id);
+                        let result = atw_thw.track(&req_id, clos(args_to_vec(&args)), true).await;
+                        send_result(result, atw_thw, &req_id);
                     });
                 })),
                 _phantom: PhantomData,
@@ -71,7 +133,32 @@ impl<T> Job<T> where T: Future<Output = ResultJJ> + 'static {
     }
     pub fn run(jsv: &JsValue, atw_thw: Rc<AtwThreadWorker>, req_id: &str) {
         let ab = jsv.dyn_ref::<ArrayBuffer>().unwrap();
-        (Self::from_ab(ab).clos_fold)(atw_thw, String::from(req_id));
+        (Self::from_ab(ab).clos_fold)(atw_thw, String::from(req_id), JsValue::UNDEFINED);
+    }
+    // `jsv` is an array of the job's `ArrayBuffer` and its args
+    pub fn run_with_args(jsv: &JsValue, atw_thw: Rc<AtwThreadWorker>, req_id: &str) {
+        let arr = jsv.dyn_ref::<Array>().unwrap();
+        let ab = arr.get(0);
+        (Self::from_ab(&ab).clos_fold)(atw_thw, String::from(req_id), arr.get(1));
+    }
+    // `jsv` is an array of jobs' `ArrayBuffer`s, run one after another; responds
+    // with an array of `[isOk, result, ms]` triples, `ms` being the run time
+    pub fn run_batch(jsv: &JsValue, atw_thw: Rc<AtwThreadWorker>, req_id: &str) {
+        let abs = jsv.dyn_ref::<Array>().unwrap().clone();
+        let req_id = req_id.to_string();
+        spawn_local(async move {
+            let results = Array::new();
+            for (i, ab) in abs.iter().enumerate() {
+                let sub_id = format!("{}/{}", req_id, i);
+                let started_at = js_sys::Date::now();
+                let result = atw_thw.run_local(&sub_id, |sub_id| Self::run(&ab, atw_thw.clone(), sub_id)).await;
+                let ms = js_sys::Date::now() - started_at;
+                let is_ok = result.is_ok();
+                let jsv = result.unwrap_or_else(|jsv| jsv);
+                results.push(&Array::of3(&JsValue::from(is_ok), &jsv, &JsValue::from(ms)));
+            }
+            atw_thw.send_response(&req_id, &results, None);
+        });
     }
     fn to_ab(&self) -> ArrayBuffer {
         let vec = bincode::serialize(&self.clos_fold).unwrap();
@@ -9,7 +9,7 @@
 //! You can run all the following apps in browser!
 //!
 //! - **exec** - How to use <code>wasm_mt</code>. [ [live](https://w3reality.github.io/wasm-mt/examples/exec/index.html) | [source](https://github.com/w3reality/wasm-mt/tree/master/examples/exec) ]
-//! - **fib** - Computing a Fibonacci sequence with nested threads. [ [live](https://w3reality.github.io/wasm-mt/examples/fib/index.html) | [source](https://github.com/w3reality/wasm-mt/tree/master/examples/fib) ]
+//! - **fib** - Computing a Fibonacci sequence with nested jobs of a thread pool. [ [live](https://w3reality.github.io/wasm-mt/examples/fib/index.html) | [source](https://github.com/w3reality/wasm-mt/tree/master/examples/fib) ]
 //! - **executors** - Minimal serial/parallel executors using <code>wasm_mt</code>. [ [live](https://w3reality.github.io/wasm-mt/examples/executors/index.html) | [source](https://github.com/w3reality/wasm-mt/tree/master/examples/executors) ]
 //! - **parallel** - Julia set benchmark of serial/parallel executors. [ [live](https://w3reality.github.io/wasm-mt/examples/parallel/index.html) | [source](https://github.com/w3reality/wasm-mt/tree/master/examples/parallel) ]
 //! - **arraybuffers** - Demo of using <code>WasmMt::new_with_arraybuffers()</code>. [ [live](https://w3reality.github.io/wasm-mt/examples/arraybuffers/index.html) | [source](https://github.com/w3reality/wasm-mt/tree/master/examples/arraybuffers) ]
@@ -178,6 +178,25 @@
 //! (4) ans: JsValue(42)
 //! ```
 //!
+//! # Sharing memory across threads
+//!
+//! For builds with `RUSTFLAGS='-C target-feature=+atomics,+bulk-memory'` (served with the cross-origin isolation headers that enable `SharedArrayBuffer`), [`with_shared_memory`][WasmMt::with_shared_memory] makes all the threads instantiate the wasm module on the memory of the main thread. Each thread gets its own stack and TLS, while the heap and statics are shared, so that e.g. atomics can be updated from the threads without serialization:
+//!
+//! ```rust
+//! static COUNT: AtomicUsize = AtomicUsize::new(0);
+//!
+//! let mt = WasmMt::new(pkg_js).with_shared_memory().and_init().await?;
+//! let th = mt.thread().and_init().await?;
+//!
+//! exec!(th, move || {
+//!     COUNT.fetch_add(1, Ordering::SeqCst);
+//!     Ok(JsValue::NULL)
+//! }).await?;
+//! assert_eq!(COUNT.load(Ordering::SeqCst), 1);
+//! ```
+//!
+//! With the `rayon` feature enabled, [`rayon::thread_pool`] builds a [`rayon::ThreadPool`](https://docs.rs/rayon/latest/rayon/struct.ThreadPool.html) running on such threads, so that rayon-based crates work in the browser as well.
+//!
 //! [async closure]: https://github.com/rust-lang/rfcs/blob/master/text/2394-async_await.md#async--closures
 //! [work stealing]: https://en.wikipedia.org/wiki/Work_stealing
 
@@ -185,18 +204,27 @@
 #![feature(async_closure)]
 
 use wasm_bindgen::prelude::*;
-use js_sys::{ArrayBuffer, Object, Reflect};
+use js_sys::{Array, ArrayBuffer, Object, Reflect};
 use std::cell::RefCell;
+use std::fmt;
 
 pub mod prelude;
 pub mod utils;
+pub mod sync;
+pub mod spsc;
 mod job;
 mod atw;
 mod worker;
 mod thread;
+mod sync_call;
+#[cfg(feature = "rayon")]
+pub mod rayon;
 
-pub use job::{MtClosure, MtAsyncClosure};
+pub use job::{MtClosure, MtAsyncClosure, MtClosureArgs, MtAsyncClosureArgs, clos_to_ab, aclos_to_ab,
+    clos_args_to_ab, aclos_args_to_ab};
 pub use thread::Thread;
+pub use worker::{in_worker, call_main, run_ab};
+pub use sync_call::{sync_call, sync_call_with_timeout, ThreadHandle, DEFAULT_SYNC_CALL_TIMEOUT_MS};
 
 #[macro_export]
 macro_rules! console_ln {
@@ -226,11 +254,40 @@ macro_rules! exec_js { ($th:expr, $str:expr) => (($th).exec_js($str)); }
 #[macro_export]
 macro_rules! exec_js_async { ($th:expr, $str:expr) => (($th).exec_js_async($str)); }
 
+// Lists the threads that failed to init with their errors, by index in
+// `WasmMt::threads()`, or by thread id in a pool
+#[derive(Debug)]
+pub struct InitError {
+    pub failed: Vec<(usize, JsValue)>,
+}
+
+impl fmt::Display for InitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WasmMt: failed to init threads: {:?}", self.failed)
+    }
+}
+
+impl std::error::Error for InitError {}
+
+// An `Error` with the entries kept as `[index, error]` pairs in its `failed`
+impl From<InitError> for JsValue {
+    fn from(err: InitError) -> Self {
+        let failed = err.failed.iter()
+            .map(|(idx, jsv)| Array::of2(&JsValue::from(*idx as u32), jsv))
+            .collect::<Array>();
+        let jsv: JsValue = js_sys::Error::new(&err.to_string()).into();
+        Reflect::set(&jsv, &JsValue::from("failed"), &failed).unwrap();
+        jsv
+    }
+}
+
 pub struct WasmMt {
     pkg_js_uri: Option<String>,
     ab_init: RefCell<Option<ArrayBuffer>>,
     ab_wasm: RefCell<Option<ArrayBuffer>>,
     is_initialized: RefCell<bool>,
+    is_shared_memory: bool,
+    memory: RefCell<Option<JsValue>>,
 }
 
 impl WasmMt {
@@ -242,6 +299,8 @@ impl WasmMt {
             ab_init: RefCell::new(None),
             ab_wasm: RefCell::new(None),
             is_initialized: RefCell::new(false),
+            is_shared_memory: false,
+            memory: RefCell::new(None),
         }
     }
 
@@ -253,9 +312,22 @@ impl WasmMt {
             ab_init: RefCell::new(Some(ab_init)),
             ab_wasm: RefCell::new(Some(ab_wasm)),
             is_initialized: RefCell::new(false),
+            is_shared_memory: false,
+            memory: RefCell::new(None),
         }
     }
 
+    // Threads get instantiated on the wasm memory of the current thread,
+    // which requires a build with `+atomics,+bulk-memory`; see `init()`
+    pub fn with_shared_memory(mut self) -> Self {
+        self.is_shared_memory = true;
+        self
+    }
+
+    pub fn is_shared_memory(&self) -> bool {
+        self.is_shared_memory
+    }
+
     pub fn set_ab_init(&self, ab: ArrayBuffer) {
         self.ab_init.replace(Some(ab));
     }
@@ -266,6 +338,13 @@ impl WasmMt {
 
     pub async fn init(&self) -> Result<&Self, JsValue> {
         assert!(!*self.is_initialized.borrow());
+        if self.is_shared_memory {
+            if !utils::is_shared_memory_available() {
+                return Err(JsValue::from(
+                    "WasmMt: shared memory requires a build with `+atomics,+bulk-memory` and cross-origin isolation"));
+            }
+            self.memory.replace(Some(wasm_bindgen::memory()));
+        }
         self.is_initialized.replace(true);
 
         if let Some(ref pkg_js_uri) = self.pkg_js_uri {
@@ -300,9 +379,27 @@ impl WasmMt {
         assert!(*self.is_initialized.borrow());
 
         // https://rustwasm.github.io/wasm-bindgen/api/js_sys/struct.ArrayBuffer.html#method.slice
-        Thread::new(
-            self.ab_init.borrow().as_ref().unwrap().slice(0),
-            self.ab_wasm.borrow().as_ref().unwrap().slice(0))
+        let ab_init = self.ab_init.borrow().as_ref().unwrap().slice(0);
+        let ab_wasm = self.ab_wasm.borrow().as_ref().unwrap().slice(0);
+        match *self.memory.borrow() {
+            Some(ref memory) => Thread::new_with_memory(ab_init, ab_wasm, memory.clone()),
+            None => Thread::new(ab_init, ab_wasm),
+        }
+    }
+
+    // Bootstrap `num` threads concurrently, returning once all of them are ready
+    pub async fn threads(&self, num: usize) -> Result<Vec<Thread>, InitError> {
+        let ths: Vec<_> = (0..num).map(|_| self.thread()).collect();
+        let results = utils::join_all(ths.iter().map(|th| th.init()).collect()).await;
+
+        let failed: Vec<_> = results.into_iter().enumerate()
+            .filter_map(|(idx, result)| result.err().map(|jsv| (idx, jsv)))
+            .collect();
+        if !failed.is_empty() {
+            return Err(InitError { failed }); // the threads ready get terminated on drop
+        }
+
+        Ok(ths)
     }
 
     fn ab_init_from(pkg_js: &str) -> ArrayBuffer {
@@ -1,2 +1,2 @@
 pub use super::{WasmMt, exec, exec_js, exec_js_async};
-pub use serde_closure::FnOnce;
+pub use serde_closure::{Fn, FnOnce};
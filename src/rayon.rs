@@ -0,0 +1,54 @@
+use crate::debug_ln;
+use ::rayon::{ThreadBuilder, ThreadPool, ThreadPoolBuilder};
+use serde_closure::FnOnce;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::spawn_local;
+use std::sync::Mutex;
+use super::{WasmMt, Thread};
+
+// Note that the main thread of browsers may not block, so e.g.
+// `ThreadPool::install()` is to be called from within a thread.
+pub async fn thread_pool(mt: &WasmMt, num_threads: usize) -> Result<ThreadPool, JsValue> {
+    thread_pool_with(mt, num_threads, ThreadPoolBuilder::new()).await
+}
+
+// The threads are bootstrapped by `WasmMt::threads()` before `builder`
+// hands them over to rayon; `num_threads` overrides that of `builder`.
+pub async fn thread_pool_with(mt: &WasmMt, num_threads: usize, builder: ThreadPoolBuilder) -> Result<ThreadPool, JsValue> {
+    assert!(num_threads > 0);
+    if !mt.is_shared_memory() {
+        return Err(JsValue::from("rayon: requires `WasmMt::with_shared_memory()`"));
+    }
+
+    // Handed out in order, i.e. the thread of index `tb.index()` to `tb`
+    let mut ths = mt.threads(num_threads).await?.into_iter();
+    builder.num_threads(num_threads)
+        .spawn_handler(move |tb| {
+            spawn_local(run(ths.next().unwrap(), tb));
+            Ok(())
+        })
+        .build()
+        .map_err(|err| JsValue::from(format!("rayon: {}", err)))
+}
+
+// Returns once the pool is dropped, upon which `th` gets terminated
+async fn run(th: Thread, tb: ThreadBuilder) {
+    // `tb` is handed over by its address on the shared memory; the slot is
+    // freed here, along with `tb` if the job never got to take it
+    let ptr = Box::into_raw(Box::new(Mutex::new(Some(tb)))) as usize;
+    let result = th.exec({ #[allow(warnings)] {
+        FnOnce!(move || {
+            let slot = unsafe { &*(ptr as *const Mutex<Option<ThreadBuilder>>) };
+            let tb = slot.lock().unwrap().take();
+            if let Some(tb) = tb {
+                tb.run();
+            }
+            Ok(JsValue::NULL)
+        })
+    }}).await;
+    drop(unsafe { Box::from_raw(ptr as *mut Mutex<Option<ThreadBuilder>>) });
+
+    if let Err(ref jsv) = result {
+        debug_ln!("rayon: thread {:?} exited: {:?}", th.get_id(), jsv);
+    }
+}
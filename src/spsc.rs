@@ -0,0 +1,308 @@
+//! A single-producer/single-consumer channel over a `SharedArrayBuffer` ring
+//! buffer, for messaging between threads without `postMessage()`.
+//!
+//! Either end is moved into a job by [`into_js`][Sender::into_js], e.g. as an
+//! arg of [`Thread::exec_with_args`][crate::Thread::exec_with_args], and restored
+//! there by [`from_js`][Sender::from_js], once only.  Dropping either end closes
+//! the channel.  As with [`wasm_mt::sync`][crate::sync], the blocking methods are
+//! for workers only; the main thread uses the `try_*` and `*_async` ones.
+
+use std::marker::PhantomData;
+use serde::{de::DeserializeOwned, Serialize};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use js_sys::{Int32Array, SharedArrayBuffer, Uint8Array};
+use super::sync::Cells;
+
+// Header cells: the read and write positions in bytes, which wrap around,
+// whether the channel is closed, a count of the changes to these that either
+// end waits on, and whether each end is held, i.e. not moved out by `into_js()`
+const HEAD: u32 = 0;
+const TAIL: u32 = 1;
+const CLOSED: u32 = 2;
+const EPOCH: u32 = 3;
+const SENDER_HELD: u32 = 4;
+const RECEIVER_HELD: u32 = 5;
+const NUM_CELLS: u32 = 6;
+const HEADER_BYTES: u32 = 32;
+
+// Items are framed by their length as a little-endian `u32`
+const FRAME_BYTES: u32 = 4;
+
+struct Ring {
+    ctl: Int32Array,
+    data: Uint8Array,
+}
+
+impl Ring {
+    fn new(capacity: u32) -> Self {
+        let sab = SharedArrayBuffer::new(HEADER_BYTES + capacity.max(16).next_power_of_two());
+        Self::from_sab(&sab)
+    }
+
+    fn from_sab(sab: &JsValue) -> Self {
+        Self {
+            ctl: Int32Array::new_with_byte_offset_and_length(sab, 0, NUM_CELLS),
+            data: Uint8Array::new_with_byte_offset(sab, HEADER_BYTES),
+        }
+    }
+
+    // Restore an end moved out by `into_js()`, failing if already restored
+    fn claim(jsv: &JsValue, held: u32) -> Result<Self, JsValue> {
+        if !jsv.is_instance_of::<SharedArrayBuffer>() {
+            return Err(JsValue::from("spsc: not a channel end"));
+        }
+        let ring = Self::from_sab(jsv);
+        if ring.cells().compare_exchange(held, 0, 1) != 0 {
+            return Err(JsValue::from("spsc: channel end already restored"));
+        }
+        Ok(ring)
+    }
+
+    fn release(&self, held: u32) -> JsValue {
+        self.cells().store(held, 0);
+        self.sab()
+    }
+
+    fn sab(&self) -> JsValue {
+        self.ctl.buffer().into()
+    }
+
+    fn cells(&self) -> Cells {
+        Cells(&self.ctl)
+    }
+
+    fn capacity(&self) -> u32 {
+        self.data.length()
+    }
+
+    fn pos(&self, idx: u32) -> u32 {
+        self.cells().load(idx) as u32
+    }
+
+    fn is_closed(&self) -> bool {
+        self.cells().load(CLOSED) != 0
+    }
+
+    fn close(&self) {
+        self.cells().store(CLOSED, 1);
+        self.bump();
+    }
+
+    fn epoch(&self) -> i32 {
+        self.cells().load(EPOCH)
+    }
+
+    fn bump(&self) {
+        self.cells().add(EPOCH, 1);
+        self.cells().notify_all(EPOCH);
+    }
+
+    fn write_at(&self, pos: u32, bytes: &[u8]) {
+        let start = pos & (self.capacity() - 1);
+        let first = ((self.capacity() - start) as usize).min(bytes.len());
+        self.data.subarray(start, start + first as u32).copy_from(&bytes[..first]);
+        if first < bytes.len() {
+            self.data.subarray(0, (bytes.len() - first) as u32).copy_from(&bytes[first..]);
+        }
+    }
+
+    fn read_at(&self, pos: u32, buf: &mut [u8]) {
+        let start = pos & (self.capacity() - 1);
+        let first = ((self.capacity() - start) as usize).min(buf.len());
+        self.data.subarray(start, start + first as u32).copy_to(&mut buf[..first]);
+        if first < buf.len() {
+            let len = (buf.len() - first) as u32;
+            self.data.subarray(0, len).copy_to(&mut buf[first..]);
+        }
+    }
+
+    // Returns `false` if there is no room for the item at the moment
+    fn try_write(&self, bytes: &[u8]) -> bool {
+        let len = FRAME_BYTES + bytes.len() as u32;
+        let (head, tail) = (self.pos(HEAD), self.pos(TAIL));
+        if self.capacity() - tail.wrapping_sub(head) < len {
+            return false;
+        }
+
+        self.write_at(tail, &(bytes.len() as u32).to_le_bytes());
+        self.write_at(tail.wrapping_add(FRAME_BYTES), bytes);
+        self.cells().store(TAIL, tail.wrapping_add(len) as i32);
+        self.bump();
+        true
+    }
+
+    fn try_read(&self) -> Option<Vec<u8>> {
+        let (head, tail) = (self.pos(HEAD), self.pos(TAIL));
+        if head == tail {
+            return None;
+        }
+
+        let mut frame = [0; FRAME_BYTES as usize];
+        self.read_at(head, &mut frame);
+        let mut buf = vec![0; u32::from_le_bytes(frame) as usize];
+        self.read_at(head.wrapping_add(FRAME_BYTES), &mut buf);
+        self.cells().store(HEAD, head.wrapping_add(FRAME_BYTES + buf.len() as u32) as i32);
+        self.bump();
+        Some(buf)
+    }
+}
+
+// `capacity` in bytes is rounded up to a power of two; each item takes its
+// bincode encoding plus 4 bytes.
+pub fn channel<T>(capacity: u32) -> (Sender<T>, Receiver<T>) where T: Serialize + DeserializeOwned {
+    let ring = Ring::new(capacity);
+    ring.cells().store(SENDER_HELD, 1);
+    ring.cells().store(RECEIVER_HELD, 1);
+    let sab = ring.sab();
+    (Sender::new(ring), Receiver::new(Ring::from_sab(&sab)))
+}
+
+pub struct Sender<T> {
+    ring: Option<Ring>, // `None` once moved out by `into_js()`
+    _phantom: PhantomData<T>,
+}
+
+impl<T> Sender<T> where T: Serialize {
+    fn new(ring: Ring) -> Self {
+        Self { ring: Some(ring), _phantom: PhantomData }
+    }
+
+    pub fn into_js(mut self) -> JsValue {
+        self.ring.take().unwrap().release(SENDER_HELD)
+    }
+
+    pub fn from_js(jsv: &JsValue) -> Result<Self, JsValue> {
+        Ring::claim(jsv, SENDER_HELD).map(Self::new)
+    }
+
+    fn ring(&self) -> &Ring {
+        self.ring.as_ref().unwrap()
+    }
+
+    fn encode(&self, item: &T) -> Result<Vec<u8>, JsValue> {
+        let bytes = bincode::serialize(item)
+            .map_err(|err| JsValue::from(format!("spsc: failed to encode: {}", err)))?;
+        if FRAME_BYTES + bytes.len() as u32 > self.ring().capacity() {
+            return Err(JsValue::from("spsc: item larger than the capacity"));
+        }
+        Ok(bytes)
+    }
+
+    pub fn try_send(&self, item: &T) -> Result<(), JsValue> {
+        let bytes = self.encode(item)?;
+        if self.ring().is_closed() {
+            Err(JsValue::from("spsc: closed"))
+        } else if self.ring().try_write(&bytes) {
+            Ok(())
+        } else {
+            Err(JsValue::from("spsc: full"))
+        }
+    }
+
+    // Blocks while the channel is full
+    pub fn send(&self, item: &T) -> Result<(), JsValue> {
+        let bytes = self.encode(item)?;
+        loop {
+            let epoch = self.ring().epoch();
+            if self.ring().is_closed() {
+                return Err(JsValue::from("spsc: closed"));
+            }
+            if self.ring().try_write(&bytes) {
+                return Ok(());
+            }
+            self.ring().cells().wait(EPOCH, epoch);
+        }
+    }
+
+    pub async fn send_async(&self, item: &T) -> Result<(), JsValue> {
+        let bytes = self.encode(item)?;
+        loop {
+            let epoch = self.ring().epoch();
+            if self.ring().is_closed() {
+                return Err(JsValue::from("spsc: closed"));
+            }
+            if self.ring().try_write(&bytes) {
+                return Ok(());
+            }
+            self.ring().cells().wait_async(EPOCH, epoch).await;
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if let Some(ref ring) = self.ring {
+            ring.close();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    ring: Option<Ring>,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> Receiver<T> where T: DeserializeOwned {
+    fn new(ring: Ring) -> Self {
+        Self { ring: Some(ring), _phantom: PhantomData }
+    }
+
+    pub fn into_js(mut self) -> JsValue {
+        self.ring.take().unwrap().release(RECEIVER_HELD)
+    }
+
+    pub fn from_js(jsv: &JsValue) -> Result<Self, JsValue> {
+        Ring::claim(jsv, RECEIVER_HELD).map(Self::new)
+    }
+
+    fn ring(&self) -> &Ring {
+        self.ring.as_ref().unwrap()
+    }
+
+    // `None` if empty at the moment
+    pub fn try_recv(&self) -> Result<Option<T>, JsValue> {
+        match self.ring().try_read() {
+            Some(buf) => bincode::deserialize(&buf).map(Some)
+                .map_err(|err| JsValue::from(format!("spsc: failed to decode: {}", err))),
+            None => Ok(None),
+        }
+    }
+
+    // Blocks while the channel is empty; `None` once closed and drained
+    pub fn recv(&self) -> Result<Option<T>, JsValue> {
+        loop {
+            let epoch = self.ring().epoch();
+            let is_closed = self.ring().is_closed();
+            if let Some(item) = self.try_recv()? {
+                return Ok(Some(item));
+            }
+            if is_closed {
+                return Ok(None);
+            }
+            self.ring().cells().wait(EPOCH, epoch);
+        }
+    }
+
+    pub async fn recv_async(&self) -> Result<Option<T>, JsValue> {
+        loop {
+            let epoch = self.ring().epoch();
+            let is_closed = self.ring().is_closed();
+            if let Some(item) = self.try_recv()? {
+                return Ok(Some(item));
+            }
+            if is_closed {
+                return Ok(None);
+            }
+            self.ring().cells().wait_async(EPOCH, epoch).await;
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        if let Some(ref ring) = self.ring {
+            ring.close();
+        }
+    }
+}
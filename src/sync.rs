@@ -0,0 +1,332 @@
+//! Synchronization primitives over `SharedArrayBuffer`, usable across threads
+//! without shared wasm memory.
+//!
+//! Each one is passed into jobs by [`to_js`][Mutex::to_js], e.g. as an arg of
+//! [`Thread::exec_with_args`][crate::Thread::exec_with_args], and restored there by
+//! [`from_js`][Mutex::from_js]; the underlying buffer is shared, not copied.
+//! The blocking methods rely on `Atomics.wait()`, which browsers do not allow
+//! on the main thread; use the `try_*` ones there instead.
+
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::JsFuture;
+use js_sys::{Atomics, Function, Int32Array, Promise, Reflect, SharedArrayBuffer};
+use super::utils;
+
+fn new_i32s(len: u32) -> Int32Array {
+    Int32Array::new(&SharedArrayBuffer::new(len * 4))
+}
+
+// Only an `Int32Array` over a `SharedArrayBuffer` with `len` cells is taken
+fn i32s_from_js(jsv: &JsValue, len: u32) -> Result<Int32Array, JsValue> {
+    jsv.dyn_ref::<Int32Array>()
+        .filter(|arr| arr.length() == len && arr.buffer().is_instance_of::<SharedArrayBuffer>())
+        .cloned()
+        .ok_or_else(|| JsValue::from("sync: not a matching primitive"))
+}
+
+macro_rules! impl_js {
+    ($ty:ident, $len:expr) => {
+        impl $ty {
+            pub fn to_js(&self) -> JsValue {
+                self.arr.clone().into()
+            }
+
+            pub fn from_js(jsv: &JsValue) -> Result<Self, JsValue> {
+                Ok(Self { arr: i32s_from_js(jsv, $len)? })
+            }
+        }
+    };
+}
+
+pub(crate) struct Cells<'a>(pub(crate) &'a Int32Array);
+
+impl Cells<'_> {
+    pub(crate) fn load(&self, idx: u32) -> i32 {
+        Atomics::load(self.0, idx).unwrap_throw()
+    }
+
+    pub(crate) fn store(&self, idx: u32, val: i32) {
+        Atomics::store(self.0, idx, val).unwrap_throw();
+    }
+
+    // These return the previous value
+    pub(crate) fn add(&self, idx: u32, val: i32) -> i32 {
+        Atomics::add(self.0, idx, val).unwrap_throw()
+    }
+
+    pub(crate) fn sub(&self, idx: u32, val: i32) -> i32 {
+        Atomics::sub(self.0, idx, val).unwrap_throw()
+    }
+
+    pub(crate) fn exchange(&self, idx: u32, val: i32) -> i32 {
+        Atomics::exchange(self.0, idx, val).unwrap_throw()
+    }
+
+    pub(crate) fn compare_exchange(&self, idx: u32, expected: i32, val: i32) -> i32 {
+        Atomics::compare_exchange(self.0, idx, expected, val).unwrap_throw()
+    }
+
+    // Blocks while the value at `idx` is `val`
+    pub(crate) fn wait(&self, idx: u32, val: i32) {
+        Atomics::wait(self.0, idx, val).unwrap_throw();
+    }
+
+    // Like `wait()` but without blocking, e.g. on the main thread; by
+    // `Atomics.waitAsync()` where available, or else by polling
+    pub(crate) async fn wait_async(&self, idx: u32, val: i32) {
+        let atomics = Reflect::get(&js_sys::global(), &JsValue::from("Atomics")).unwrap_throw();
+        let wait_async = Reflect::get(&atomics, &JsValue::from("waitAsync")).unwrap_throw();
+        if !wait_async.is_function() {
+            while self.load(idx) == val {
+                utils::sleep(1).await;
+            }
+            return;
+        }
+
+        let result = wait_async.unchecked_ref::<Function>()
+            .call3(&atomics, self.0, &JsValue::from(idx), &JsValue::from(val)).unwrap_throw();
+        if Reflect::get(&result, &JsValue::from("async")).unwrap_throw().is_truthy() {
+            let promise = Reflect::get(&result, &JsValue::from("value")).unwrap_throw();
+            let _ = JsFuture::from(promise.unchecked_into::<Promise>()).await;
+        }
+    }
+
+    pub(crate) fn notify(&self, idx: u32, count: u32) {
+        Atomics::notify_with_count(self.0, idx, count).unwrap_throw();
+    }
+
+    pub(crate) fn notify_all(&self, idx: u32) {
+        Atomics::notify(self.0, idx).unwrap_throw();
+    }
+}
+
+pub struct AtomicCounter {
+    arr: Int32Array,
+}
+
+impl_js!(AtomicCounter, 1);
+
+impl AtomicCounter {
+    pub fn new(val: i32) -> Self {
+        let arr = new_i32s(1);
+        arr.set_index(0, val);
+        Self { arr }
+    }
+
+    fn cells(&self) -> Cells {
+        Cells(&self.arr)
+    }
+
+    pub fn get(&self) -> i32 {
+        self.cells().load(0)
+    }
+
+    pub fn set(&self, val: i32) {
+        self.cells().store(0, val);
+    }
+
+    // Returns the new value
+    pub fn add(&self, val: i32) -> i32 {
+        self.cells().add(0, val) + val
+    }
+
+    pub fn inc(&self) -> i32 {
+        self.add(1)
+    }
+
+    pub fn dec(&self) -> i32 {
+        self.add(-1)
+    }
+}
+
+// Unlocked (0), locked (1), or locked with threads possibly waiting (2)
+pub struct Mutex {
+    arr: Int32Array,
+}
+
+impl_js!(Mutex, 1);
+
+pub struct MutexGuard<'a>(&'a Mutex);
+
+impl Drop for MutexGuard<'_> {
+    fn drop(&mut self) {
+        self.0.unlock();
+    }
+}
+
+impl Mutex {
+    pub fn new() -> Self {
+        Self { arr: new_i32s(1) }
+    }
+
+    fn cells(&self) -> Cells {
+        Cells(&self.arr)
+    }
+
+    pub fn lock(&self) -> MutexGuard {
+        let cells = self.cells();
+        let mut state = cells.compare_exchange(0, 0, 1);
+        if state != 0 {
+            if state != 2 {
+                state = cells.exchange(0, 2);
+            }
+            while state != 0 {
+                cells.wait(0, 2);
+                state = cells.exchange(0, 2);
+            }
+        }
+        MutexGuard(self)
+    }
+
+    pub fn try_lock(&self) -> Option<MutexGuard> {
+        match self.cells().compare_exchange(0, 0, 1) {
+            0 => Some(MutexGuard(self)),
+            _ => None,
+        }
+    }
+
+    fn unlock(&self) {
+        let cells = self.cells();
+        if cells.sub(0, 1) != 1 {
+            cells.store(0, 0);
+            cells.notify(0, 1);
+        }
+    }
+}
+
+impl Default for Mutex {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct Semaphore {
+    arr: Int32Array,
+}
+
+impl_js!(Semaphore, 1);
+
+impl Semaphore {
+    pub fn new(permits: i32) -> Self {
+        assert!(permits >= 0);
+        let arr = new_i32s(1);
+        arr.set_index(0, permits);
+        Self { arr }
+    }
+
+    fn cells(&self) -> Cells {
+        Cells(&self.arr)
+    }
+
+    pub fn acquire(&self) {
+        while !self.try_acquire() {
+            self.cells().wait(0, 0);
+        }
+    }
+
+    pub fn try_acquire(&self) -> bool {
+        let cells = self.cells();
+        loop {
+            let permits = cells.load(0);
+            if permits == 0 {
+                return false;
+            }
+            if cells.compare_exchange(0, permits, permits - 1) == permits {
+                return true;
+            }
+        }
+    }
+
+    pub fn release(&self) {
+        self.cells().add(0, 1);
+        self.cells().notify(0, 1);
+    }
+
+    pub fn available_permits(&self) -> i32 {
+        self.cells().load(0)
+    }
+}
+
+// Laid out as the number of parties, the number arrived, and the generation
+pub struct Barrier {
+    arr: Int32Array,
+}
+
+impl_js!(Barrier, 3);
+
+impl Barrier {
+    pub fn new(num: i32) -> Self {
+        assert!(num > 0);
+        let arr = new_i32s(3);
+        arr.set_index(0, num);
+        Self { arr }
+    }
+
+    fn cells(&self) -> Cells {
+        Cells(&self.arr)
+    }
+
+    // Blocks until `num` threads are waiting; returns `true` for the last one
+    // to arrive.  The barrier can be reused afterwards.
+    pub fn wait(&self) -> bool {
+        let cells = self.cells();
+        let gen = cells.load(2);
+        if cells.add(1, 1) + 1 == cells.load(0) {
+            cells.store(1, 0);
+            cells.add(2, 1);
+            cells.notify_all(2);
+            return true;
+        }
+
+        while cells.load(2) == gen {
+            cells.wait(2, gen);
+        }
+        false
+    }
+}
+
+// Releases the waiting threads once counted down to zero
+pub struct Latch {
+    arr: Int32Array,
+}
+
+impl_js!(Latch, 1);
+
+impl Latch {
+    pub fn new(count: i32) -> Self {
+        assert!(count >= 0);
+        let arr = new_i32s(1);
+        arr.set_index(0, count);
+        Self { arr }
+    }
+
+    fn cells(&self) -> Cells {
+        Cells(&self.arr)
+    }
+
+    pub fn count_down(&self) {
+        let cells = self.cells();
+        if cells.sub(0, 1) == 1 {
+            cells.notify_all(0);
+        }
+    }
+
+    pub fn count(&self) -> i32 {
+        self.cells().load(0).max(0)
+    }
+
+    pub fn wait(&self) {
+        loop {
+            let count = self.cells().load(0);
+            if count <= 0 {
+                return;
+            }
+            self.cells().wait(0, count);
+        }
+    }
+
+    pub fn try_wait(&self) -> bool {
+        self.count() == 0
+    }
+}
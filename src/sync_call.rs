@@ -0,0 +1,203 @@
+use crate::debug_ln;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::{Rc, Weak};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use wasm_bindgen_futures::spawn_local;
+use js_sys::{Array, ArrayBuffer, Atomics, Date, Int32Array, Object, Reflect, SharedArrayBuffer, Uint8Array, JSON};
+use web_sys::Worker;
+use uuid::Uuid;
+use super::atw::Thread as AtwThread;
+use super::job::{MtClosure, clos_to_ab};
+use super::utils::Counter;
+use super::{worker, encode_task_msg};
+
+type ResultJJ = Result<JsValue, JsValue>;
+
+pub const DEFAULT_SYNC_CALL_TIMEOUT_MS: f64 = 10_000.0;
+
+// Replies are passed as JSON of up to this many bytes
+const SLOT_BYTES: u32 = 64 * 1024;
+const HEADER_BYTES: u32 = 8;
+
+// Slot states, followed by the length of the reply
+const PENDING: i32 = 0;
+const OK: i32 = 1;
+const ERR: i32 = 2;
+
+// Identifies a thread for `sync_call()`; obtained on the main thread by
+// `Thread::handle()` and then captured by jobs
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ThreadHandle {
+    id: u32,
+}
+
+thread_local! {
+    // Of the main thread only
+    static PEERS: RefCell<Vec<(u32, Weak<AtwThread>)>> = RefCell::new(Vec::new());
+    static NEXT_ID: Counter = Counter::new();
+    // Workers blocked in `sync_call()`, and their peers, by call id
+    static WAITS: RefCell<HashMap<String, (JsValue, JsValue)>> = RefCell::new(HashMap::new());
+}
+
+pub(crate) fn register(atw_th: &Rc<AtwThread>) -> ThreadHandle {
+    PEERS.with(|peers| {
+        let mut peers = peers.borrow_mut();
+        peers.retain(|(_, weak)| weak.strong_count() > 0);
+        if let Some((id, _)) = peers.iter().find(|(_, weak)| weak.as_ptr() == Rc::as_ptr(atw_th)) {
+            return ThreadHandle { id: *id };
+        }
+
+        let id = NEXT_ID.with(|next| next.inc() - 1) as u32;
+        peers.push((id, Rc::downgrade(atw_th)));
+        ThreadHandle { id }
+    })
+}
+
+fn lookup(handle_id: u32) -> Option<Rc<AtwThread>> {
+    PEERS.with(|peers| peers.borrow().iter()
+        .find(|(id, _)| *id == handle_id)
+        .and_then(|(_, weak)| weak.upgrade()))
+}
+
+// Whether `target` is `caller` or, transitively, blocked on it
+fn would_deadlock(caller: &JsValue, target: &JsValue) -> bool {
+    WAITS.with(|waits| {
+        let waits = waits.borrow();
+        let mut peer = target.clone();
+        for _ in 0..=waits.len() {
+            if peer == *caller {
+                return true;
+            }
+            match waits.values().find(|(from, _)| *from == peer) {
+                Some((_, to)) => peer = to.clone(),
+                None => return false,
+            }
+        }
+        false
+    })
+}
+
+fn header(sab: &JsValue) -> Int32Array {
+    Int32Array::new_with_byte_offset_and_length(sab, 0, 2)
+}
+
+fn body(sab: &JsValue) -> Uint8Array {
+    Uint8Array::new_with_byte_offset(sab, HEADER_BYTES)
+}
+
+fn write_reply(sab: &JsValue, result: ResultJJ) {
+    let (mut state, jsv) = match result {
+        Ok(jsv) => (OK, jsv),
+        Err(jsv) => (ERR, jsv),
+    };
+    // An `Error` would come out as `{}`, so it is passed as e.g. "TypeError: msg"
+    let jsv = match jsv.dyn_ref::<js_sys::Error>() {
+        Some(err) => err.to_string().into(),
+        None => jsv,
+    };
+    let mut json = JSON::stringify(&jsv).ok()
+        .and_then(|s| s.as_string())
+        .unwrap_or_else(|| "null".to_string());
+    let body = body(sab);
+    if json.len() > body.length() as usize {
+        state = ERR;
+        json = "\"sync_call(): reply too large\"".to_string();
+    }
+
+    body.subarray(0, json.len() as u32).copy_from(json.as_bytes());
+    let header = header(sab);
+    Atomics::store(&header, 1, json.len() as i32).unwrap_throw();
+    Atomics::store(&header, 0, state).unwrap_throw();
+    Atomics::notify(&header, 0).unwrap_throw();
+}
+
+fn read_reply(sab: &JsValue) -> ResultJJ {
+    let header = header(sab);
+    let len = Atomics::load(&header, 1)? as u32;
+    let json = String::from_utf8(body(sab).subarray(0, len).to_vec())
+        .map_err(|_| JsValue::from("sync_call(): malformed reply"))?;
+    let jsv = JSON::parse(&json)?;
+    match Atomics::load(&header, 0)? {
+        OK => Ok(jsv),
+        _ => Err(jsv),
+    }
+}
+
+// On the main thread, forward a `sync_call()` by `caller` to its peer
+pub(crate) fn serve(caller: &Worker, call_id: &str, payload: &JsValue) {
+    let get = |key: &str| Reflect::get(payload, &JsValue::from(key)).unwrap_or(JsValue::UNDEFINED);
+    let sab = get("slot");
+    if !sab.is_instance_of::<SharedArrayBuffer>() {
+        debug_ln!("sync_call(): no slot to reply to; call id: {}", call_id);
+        return;
+    }
+    let (handle_id, ab) = match (get("handle").as_f64(), get("job").dyn_into::<ArrayBuffer>()) {
+        (Some(handle_id), Ok(ab)) => (handle_id as u32, ab),
+        _ => return write_reply(&sab, Err(JsValue::from("sync_call(): malformed call"))),
+    };
+
+    let target = match lookup(handle_id) {
+        Some(target) if !target.is_terminated() => target,
+        _ => return write_reply(&sab, Err(JsValue::from("sync_call(): peer not found"))),
+    };
+    let (caller, peer) = (JsValue::from(caller.clone()), JsValue::from(target.worker().clone()));
+    if would_deadlock(&caller, &peer) {
+        return write_reply(&sab, Err(JsValue::from("sync_call(): deadlock; the peer is blocked on the caller")));
+    }
+
+    let call_id = call_id.to_string();
+    WAITS.with(|waits| waits.borrow_mut().insert(call_id.clone(), (caller, peer)));
+    spawn_local(async move {
+        let msg = encode_task_msg("job-clos", Some(&ab));
+        let result = target.send_request(&msg, Some(&Array::of1(&ab))).await;
+        debug_ln!("sync_call(): result: {:?}", result);
+
+        forget(&call_id);
+        write_reply(&sab, result);
+    });
+}
+
+// The caller is no longer blocked, i.e. the call is done or timed out
+pub(crate) fn forget(call_id: &str) {
+    WAITS.with(|waits| waits.borrow_mut().remove(call_id));
+}
+
+// Like `sync_call_with_timeout()` with the default timeout.  The result is
+// likewise passed as JSON, so e.g. typed arrays do not make it through.
+pub fn sync_call<F>(handle: &ThreadHandle, job: F) -> ResultJJ where F: MtClosure {
+    sync_call_with_timeout(handle, job, DEFAULT_SYNC_CALL_TIMEOUT_MS)
+}
+
+// Run `job` on the thread of `handle`, blocking until its result; only
+// available within jobs.  The result is passed as JSON, so e.g. typed arrays
+// do not make it through, and `Error`s come as strings.
+pub fn sync_call_with_timeout<F>(handle: &ThreadHandle, job: F, timeout_ms: f64) -> ResultJJ where F: MtClosure {
+    let atw_thw = match worker::current() {
+        Some(atw_thw) => atw_thw,
+        None => return Err(JsValue::from("sync_call(): not in a worker")),
+    };
+
+    let sab: JsValue = SharedArrayBuffer::new(HEADER_BYTES + SLOT_BYTES).into();
+    let ab = clos_to_ab(job);
+    let payload = Object::new();
+    Reflect::set(&payload, &JsValue::from("handle"), &JsValue::from(handle.id)).unwrap();
+    Reflect::set(&payload, &JsValue::from("job"), &ab).unwrap();
+    Reflect::set(&payload, &JsValue::from("slot"), &sab).unwrap();
+    let call_id = Uuid::new_v4();
+    atw_thw.post_sync_call(&call_id, &payload, Some(&Array::of1(&ab)));
+
+    let header = header(&sab);
+    let deadline = Date::now() + timeout_ms;
+    while Atomics::load(&header, 0).unwrap_throw() == PENDING {
+        let ms = deadline - Date::now();
+        if ms <= 0.0 {
+            atw_thw.post_sync_call_timeout(&call_id);
+            return Err(JsValue::from("sync_call(): timed out"));
+        }
+        Atomics::wait_with_timeout(&header, 0, PENDING, ms)?;
+    }
+    read_reply(&sab)
+}
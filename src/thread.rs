@@ -4,10 +4,12 @@ use std::cell::RefCell;
 use std::future::Future;
 use std::pin::Pin;
 use wasm_bindgen::prelude::*;
-use js_sys::{Array, ArrayBuffer, Object, Reflect};
+use js_sys::{Array, ArrayBuffer, Object, Promise, Reflect};
 use web_sys::{Blob, BlobPropertyBag, Url};
+use uuid::Uuid;
 use super::atw::Thread as AtwThread;
 use super::job;
+use super::sync_call::{self, ThreadHandle};
 use super::encode_task_msg;
 
 type ResultJJ = Result<JsValue, JsValue>;
@@ -15,10 +17,12 @@ type ResultJJ = Result<JsValue, JsValue>;
 pub struct Thread {
     ab_init: RefCell<Option<ArrayBuffer>>,
     ab_wasm: RefCell<Option<ArrayBuffer>>,
-    atw_th: AtwThread,
+    memory: Option<JsValue>,
+    atw_th: Rc<AtwThread>,
     is_initialized: RefCell<bool>,
     id: RefCell<Option<Rc<String>>>,
     is_busy: RefCell<bool>,
+    idle_since: RefCell<f64>,
 }
 
 impl Thread {
@@ -39,12 +43,12 @@ impl Thread {
 
     fn get_worker_content() -> &'static str {
         "
-        const instantiate = async (abInit, abWasm) => {
+        const instantiate = async (abInit, abWasm, memory) => {
             // console.log('abInit:', abInit);
             const initJs = new TextDecoder().decode(abInit);
             const init = (new Function(initJs)).call(null);
             const wbg = init();
-            const wasm = await wbg(abWasm);
+            const wasm = await wbg(abWasm, memory); // `memory` is shared, if any
             // console.log('wbg:', wbg);
             // console.log('wasm:', wasm);
             return { wbg, wasm };
@@ -55,11 +59,11 @@ impl Thread {
             // console.log('onmessage(): e.data', e.data);
 
             const { id, payload } = e.data; // destructure the initial `atw` msg
-            const { abInit, abWasm } = payload;
+            const { abInit, abWasm, memory } = payload;
             if (first) {
                 first = false;
                 try {
-                    const { wbg, wasm } = await instantiate(abInit, abWasm);
+                    const { wbg, wasm } = await instantiate(abInit, abWasm, memory);
                     // throw 'ok, bye for now'; // !! debug
 
                     // This overrides `self.onmessage`
@@ -78,18 +82,29 @@ impl Thread {
     }
 
     pub fn new(ab_init: ArrayBuffer, ab_wasm: ArrayBuffer) -> Self {
+        Self::new_inner(ab_init, ab_wasm, None)
+    }
+
+    // `memory` is a shared `WebAssembly.Memory` the module gets instantiated on
+    pub fn new_with_memory(ab_init: ArrayBuffer, ab_wasm: ArrayBuffer, memory: JsValue) -> Self {
+        Self::new_inner(ab_init, ab_wasm, Some(memory))
+    }
+
+    fn new_inner(ab_init: ArrayBuffer, ab_wasm: ArrayBuffer, memory: Option<JsValue>) -> Self {
         let blob_url = Self::create_blob_url(Self::get_worker_content());
         debug_ln!("blob_url: {}", &blob_url);
-        let atw_th = AtwThread::new(&blob_url);
+        let atw_th = Rc::new(AtwThread::new(&blob_url));
         Self::revoke_blob_url(blob_url);
 
         Self {
             ab_init: RefCell::new(Some(ab_init)),
             ab_wasm: RefCell::new(Some(ab_wasm)),
+            memory,
             atw_th,
             is_initialized: RefCell::new(false),
             id: RefCell::new(None),
             is_busy: RefCell::new(false),
+            idle_since: RefCell::new(js_sys::Date::now()),
         }
     }
 
@@ -100,6 +115,9 @@ impl Thread {
         let payload = Object::new();
         Reflect::set(payload.as_ref(), &JsValue::from("abInit"), &ab_init).unwrap();
         Reflect::set(payload.as_ref(), &JsValue::from("abWasm"), &ab_wasm).unwrap();
+        if let Some(ref memory) = self.memory {
+            Reflect::set(payload.as_ref(), &JsValue::from("memory"), memory).unwrap();
+        }
 
         let result = self.atw_th.send_request(
             &payload, Some(&Array::of2(&ab_init, &ab_wasm))).await;
@@ -136,6 +154,42 @@ impl Thread {
         self.atw_th.send_request(&msg, Some(&Array::of1(&ab))).await
     }
 
+    // `args` are passed to the closure via structured cloning instead of serde
+    pub async fn exec_with_args<F>(&self, clos: F, args: &Array) -> ResultJJ where F: job::MtClosureArgs {
+        assert!(*self.is_initialized.borrow());
+
+        type _TypeT = Pin<Box<dyn Future<Output = ResultJJ>>>;
+        let ab = job::Job::<_TypeT>::from_clos_args(clos);
+        let msg = encode_task_msg("job-clos-args", Some(&Array::of2(&ab, args)));
+        self.atw_th.send_request(&msg, Some(&Array::of1(&ab))).await
+    }
+
+    pub async fn exec_async_with_args<F, T>(&self, aclos: F, args: &Array) -> ResultJJ where F: job::MtAsyncClosureArgs<T> {
+        assert!(*self.is_initialized.borrow());
+
+        let ab = job::Job::<T>::from_aclos_args(aclos);
+        let msg = encode_task_msg("job-aclos-args", Some(&Array::of2(&ab, args)));
+        self.atw_th.send_request(&msg, Some(&Array::of1(&ab))).await
+    }
+
+    // `ab` is a job from `clos_to_ab()` or `aclos_to_ab()`; it gets transferred
+    pub async fn exec_ab(&self, ab: &ArrayBuffer) -> ResultJJ {
+        assert!(*self.is_initialized.borrow());
+
+        let msg = encode_task_msg("job-clos", Some(ab));
+        self.atw_th.send_request(&msg, Some(&Array::of1(ab))).await
+    }
+
+    // Run several jobs from `clos_to_ab()` with a single message, resolving
+    // to an array of `[isOk, result, ms]` triples, `ms` being the run time of
+    // each; `abs` get transferred
+    pub async fn exec_batch(&self, abs: &Array) -> ResultJJ {
+        assert!(*self.is_initialized.borrow());
+
+        let msg = encode_task_msg("job-batch", Some(abs));
+        self.atw_th.send_request(&msg, Some(abs)).await
+    }
+
     pub async fn exec_js(&self, js: &str) -> ResultJJ {
         let msg = encode_task_msg("job-js", Some(&JsValue::from(js)));
         self.atw_th.send_request(&msg, None).await
@@ -146,14 +200,107 @@ impl Thread {
         self.atw_th.send_request(&msg, None).await
     }
 
+    // Id for a run by one of the `exec_*_as()` methods, by which `cancel()`
+    // can stop it
+    pub fn new_run_id(&self) -> String {
+        self.atw_th.new_run_id().to_string()
+    }
+
+    async fn send_task_as(&self, run_id: &str, name: &str, data: &JsValue) -> ResultJJ {
+        let req_id = Uuid::parse_str(run_id)
+            .map_err(|_| JsValue::from(format!("Thread: invalid run id: {}", run_id)))?;
+        self.atw_th.send_request_as(&req_id, &encode_task_msg(name, Some(data)), None).await
+    }
+
+    // Like `exec_ab()` but `ab` gets copied instead of transferred, so that it
+    // can be run again
+    pub async fn exec_ab_as(&self, run_id: &str, ab: &ArrayBuffer) -> ResultJJ {
+        assert!(*self.is_initialized.borrow());
+        self.send_task_as(run_id, "job-clos", ab).await
+    }
+
+    // `ab` is a job from `clos_args_to_ab()` or `aclos_args_to_ab()`
+    pub async fn exec_ab_with_args_as(&self, run_id: &str, ab: &ArrayBuffer, args: &Array) -> ResultJJ {
+        assert!(*self.is_initialized.borrow());
+        self.send_task_as(run_id, "job-clos-args", &Array::of2(ab, args)).await
+    }
+
+    pub async fn exec_js_as(&self, run_id: &str, js: &str) -> ResultJJ {
+        self.send_task_as(run_id, "job-js", &JsValue::from(js)).await
+    }
+
+    pub async fn exec_js_async_as(&self, run_id: &str, js: &str) -> ResultJJ {
+        self.send_task_as(run_id, "job-js-async", &JsValue::from(js)).await
+    }
+
+    // Stop the run of `run_id`, failing it with an error: a run not started
+    // yet by the worker is dropped, and an async one is aborted at the point
+    // its future is awaiting; a run already done counts as stopped.  Resolves to
+    // `false` for runs that keep going regardless, i.e. those of async JS.  A
+    // sync run in progress blocks the worker, so this only resolves once it is
+    // done; callers may want to race it against a timeout.
+    pub async fn cancel(&self, run_id: &str) -> Result<bool, JsValue> {
+        let msg = encode_task_msg("job-cancel", Some(&JsValue::from(run_id)));
+        let jsv = self.atw_th.send_request(&msg, None).await?;
+        jsv.as_bool().ok_or_else(|| JsValue::from("Thread: unexpected cancel result"))
+    }
+
+    // Size in bytes of the worker's wasm linear memory
+    pub async fn get_memory_bytes(&self) -> Result<f64, JsValue> {
+        let msg = encode_task_msg("memory-size", None);
+        let jsv = self.atw_th.send_request(&msg, None).await?;
+        jsv.as_f64().ok_or_else(|| JsValue::from(format!("Thread: invalid memory size: {:?}", jsv)))
+    }
+
     pub fn terminate(&self) {
-        self.atw_th.terminate();
+        if self.memory.is_some() && *self.is_initialized.borrow() {
+            // Let the worker free its stack and TLS on the shared memory first
+            self.atw_th.close_with(&encode_task_msg("thread-destroy", None));
+        } else {
+            self.atw_th.terminate();
+        }
+    }
+
+    // Identifies the thread for `sync_call()` made within other threads' jobs
+    pub fn handle(&self) -> ThreadHandle {
+        sync_call::register(&self.atw_th)
+    }
+
+    pub fn is_shared_memory(&self) -> bool {
+        self.memory.is_some()
     }
 
     pub fn is_terminated(&self) -> bool {
         self.atw_th.is_terminated()
     }
 
+    // Terminate the thread if it has been idle for at least `idle_ms`; returns
+    // whether it got terminated
+    pub fn terminate_if_idle(&self, idle_ms: f64) -> bool {
+        match self.get_idle_ms() {
+            Some(ms) if ms >= idle_ms => {
+                self.terminate();
+                true
+            },
+            _ => false,
+        }
+    }
+
+    // Handle the payloads of `call_main()` made by jobs on this thread
+    pub fn set_call_handler<H>(&self, handler: H) where H: Fn(JsValue) -> Promise + 'static {
+        self.atw_th.set_call_handler(Rc::new(handler));
+    }
+
+    // Called once the worker fires `onerror`, after its pending requests are rejected
+    pub fn set_error_handler<H>(&self, handler: H) where H: Fn() + 'static {
+        self.atw_th.set_error_handler(Rc::new(handler));
+    }
+
+    // Whether the worker has fired `onerror`, e.g. due to a panic in a job
+    pub fn has_error(&self) -> bool {
+        self.atw_th.has_error()
+    }
+
     pub fn get_id(&self) -> Option<Rc<String>> {
         self.id.borrow().as_ref().cloned()
     }
@@ -168,7 +315,19 @@ impl Thread {
     }
 
     pub fn set_busy(&self, tf: bool) -> &Self {
+        if !tf && self.get_busy() {
+            self.idle_since.replace(js_sys::Date::now());
+        }
         self.is_busy.replace(tf);
         self
     }
+
+    // Milliseconds elapsed since the thread last became idle; `None` while busy
+    pub fn get_idle_ms(&self) -> Option<f64> {
+        if self.get_busy() {
+            None
+        } else {
+            Some(js_sys::Date::now() - *self.idle_since.borrow())
+        }
+    }
 }
@@ -2,11 +2,14 @@ extern crate wasm_bindgen;
 use wasm_bindgen::prelude::*;
 use wasm_bindgen_futures::JsFuture;
 use wasm_bindgen::JsCast;
-use js_sys::{ArrayBuffer, Function, Promise, Uint8Array};
+use js_sys::{ArrayBuffer, Function, Promise, Reflect, Uint8Array};
 use web_sys::{Response, TextDecoder, TextEncoder};
 
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
 pub use super::{console_ln, debug_ln};
 
@@ -93,6 +96,37 @@ pub async fn fetch_as_arraybuffer(url: &str) -> Result<ArrayBuffer, JsValue> {
     Ok(ret)
 }
 
+// `navigator.hardwareConcurrency` in window/worker scopes; `os.availableParallelism()` under NodeJS
+pub fn hardware_concurrency() -> Option<usize> {
+    let navigator = Reflect::get(&js_sys::global(), &JsValue::from("navigator")).ok()?;
+    let num = if navigator.is_undefined() {
+        run_js("
+            const proc = globalThis.process;
+            if (!proc || !proc.versions || !proc.versions.node) return undefined;
+            const os = proc.getBuiltinModule ? proc.getBuiltinModule('os') :
+                typeof require === 'function' ? require('os') :
+                proc.mainModule ? proc.mainModule.require('os') : undefined;
+            if (!os) return undefined;
+            return os.availableParallelism ? os.availableParallelism() : os.cpus().length;
+        ").ok()?
+    } else {
+        Reflect::get(&navigator, &JsValue::from("hardwareConcurrency")).ok()?
+    };
+
+    match num.as_f64() {
+        Some(num) if num >= 1.0 => Some(num as usize),
+        _ => None,
+    }
+}
+
+// Whether the wasm memory of the current thread is backed by a `SharedArrayBuffer`
+pub fn is_shared_memory_available() -> bool {
+    let buffer = wasm_bindgen::memory().unchecked_into::<js_sys::WebAssembly::Memory>().buffer();
+    Reflect::get(&buffer, &JsValue::from("constructor"))
+        .and_then(|ctor| Reflect::get(&ctor, &JsValue::from("name")))
+        .map_or(false, |name| name == "SharedArrayBuffer")
+}
+
 pub fn run_js(js: &str) -> Result<JsValue, JsValue> {
     Function::new_no_args(js).call0(&JsValue::NULL)
 }
@@ -118,3 +152,59 @@ pub async fn sleep(ms: u32) {
         await (ms => new Promise((res, rej) => setTimeout(res, ms)))({});
     ", ms).as_str()).await.unwrap();
 }
+
+thread_local! {
+    // Resolves a promise in a later task, by a `MessageChannel` set up once
+    static YIELD_TASK: Function = run_js("
+        const ch = new MessageChannel();
+        const queue = [];
+        ch.port1.onmessage = () => queue.shift()();
+        return () => new Promise(res => {
+            queue.push(res);
+            ch.port2.postMessage(null);
+        });
+    ").unwrap().unchecked_into();
+}
+
+// Resume in a later task, i.e. after the messages already received are handled;
+// unlike `sleep(0)`, not subject to the clamping of nested timers
+pub async fn yield_task() {
+    let promise = YIELD_TASK.with(|yield_task| yield_task.call0(&JsValue::NULL)).unwrap();
+    JsFuture::from(promise.unchecked_into::<Promise>()).await.unwrap();
+}
+
+// Await all of `futs` concurrently, with their outputs in order
+pub async fn join_all<F: Future>(futs: Vec<F>) -> Vec<F::Output> {
+    JoinAll {
+        outputs: futs.iter().map(|_| None).collect(),
+        futs: futs.into_iter().map(Box::pin).collect(),
+    }.await
+}
+
+struct JoinAll<F: Future> {
+    futs: Vec<Pin<Box<F>>>,
+    outputs: Vec<Option<F::Output>>,
+}
+
+impl<F: Future> Future for JoinAll<F> {
+    type Output = Vec<F::Output>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        let this = &mut *self;
+        let mut is_done = true;
+        for (fut, output) in this.futs.iter_mut().zip(this.outputs.iter_mut()) {
+            if output.is_none() {
+                match fut.as_mut().poll(cx) {
+                    Poll::Ready(out) => *output = Some(out),
+                    Poll::Pending => is_done = false,
+                }
+            }
+        }
+
+        if is_done {
+            Poll::Ready(this.outputs.drain(..).map(Option::unwrap).collect())
+        } else {
+            Poll::Pending
+        }
+    }
+}
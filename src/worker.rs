@@ -2,11 +2,54 @@ use crate::debug_ln;
 use std::future::Future;
 use std::pin::Pin;
 use std::rc::Rc;
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
 use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::spawn_local;
+use js_sys::{Array, ArrayBuffer};
+use uuid::Uuid;
 use web_sys::{WorkerGlobalScope, MessageEvent};
 use super::atw::{ThreadWorker as AtwThreadWorker, atw_decode_req_msg};
 use super::decode_task_msg;
 use super::job;
+use super::utils;
+
+thread_local! {
+    static ATW_THW: RefCell<Option<Rc<AtwThreadWorker>>> = RefCell::new(None);
+    // Jobs received but not started yet, so that a `job-cancel` can drop them
+    static QUEUED: RefCell<VecDeque<(String, String, JsValue)>> = RefCell::new(VecDeque::new());
+    static IS_DRAINING: Cell<bool> = Cell::new(false);
+}
+
+pub fn in_worker() -> bool {
+    ATW_THW.with(|atw_thw| atw_thw.borrow().is_some())
+}
+
+pub(crate) fn current() -> Option<Rc<AtwThreadWorker>> {
+    ATW_THW.with(|atw_thw| atw_thw.borrow().clone())
+}
+
+// Call the handler set by `Thread::set_call_handler()` on the main thread;
+// only available within jobs
+pub async fn call_main(payload: &JsValue, transfer: Option<&Array>) -> Result<JsValue, JsValue> {
+    match current() {
+        Some(atw_thw) => atw_thw.call(payload, transfer).await,
+        None => Err(JsValue::from("call_main(): not in a worker")),
+    }
+}
+
+// Run a job from `clos_to_ab()` on the current thread, e.g. one that another
+// thread declined to take; only available within jobs
+pub async fn run_ab(ab: &ArrayBuffer) -> Result<JsValue, JsValue> {
+    type TypeT = Pin<Box<dyn Future<Output = Result<JsValue, JsValue>>>>;
+    match current() {
+        Some(atw_thw) => {
+            let run_id = Uuid::new_v4().to_string();
+            atw_thw.run_local(&run_id, |run_id| job::Job::<TypeT>::run(ab, atw_thw.clone(), run_id)).await
+        },
+        None => Err(JsValue::from("run_ab(): not in a worker")),
+    }
+}
 
 #[allow(dead_code)]
 #[wasm_bindgen]
@@ -30,6 +73,7 @@ impl _Worker {
 
         let on_message = Self::create_onmessage(atw_thw.clone());
         atw_thw.set_callback_of("onmessage", on_message.as_ref());
+        ATW_THW.with(|cell| cell.replace(Some(atw_thw.clone())));
 
         Self {
             atw_thw,
@@ -41,26 +85,41 @@ impl _Worker {
         Closure::wrap(Box::new(move |me: MessageEvent| {
             let ref data = me.data();
             // debug_ln!("on_message(): data: {:?}", data);
+            if atw_thw.on_call_result(data) {
+                return;
+            }
 
             let (ref id, ref task_msg) = atw_decode_req_msg(data);
             Self::on_request_inner(atw_thw.clone(), id, task_msg);
         }) as Box<dyn FnMut(MessageEvent)>)
     }
 
+    // Deferred so as not to free the stack in use; `__wbindgen_thread_destroy`
+    // is only exported by builds with `+atomics`
+    fn destroy_thread() {
+        utils::run_js("setTimeout(() => {
+            const { wasm } = self.wmtContext;
+            if (wasm.__wbindgen_thread_destroy) { wasm.__wbindgen_thread_destroy(); }
+            self.close();
+        }, 0);").unwrap();
+    }
+
     fn on_request_inner(atw_thw: Rc<AtwThreadWorker>, req_id: &str, task_msg: &JsValue) {
         // debug_ln!("on_request_inner(): req_id: {}", req_id);
 
-        let (ref name, ref jsv) = decode_task_msg(task_msg);
+        let (name, jsv) = decode_task_msg(task_msg);
         // debug_ln!("on_request_inner(): task: {}", name);
 
-        // TODO - refactor with `enum` later
         match name.as_str() {
-            "job-clos" | "job-aclos" => {
-                type TypeT = Pin<Box<dyn Future<Output = Result<JsValue, JsValue>>>>;
-                job::Job::<TypeT>::run(jsv, atw_thw, req_id);
+            "job-clos" | "job-aclos" | "job-clos-args" | "job-aclos-args" |
+            "job-batch" | "job-js" | "job-js-async" => Self::enqueue(atw_thw, req_id, name, jsv),
+            "job-cancel" => {
+                let run_id = jsv.as_string().unwrap_throw();
+                let is_stopped = Self::dequeue(&atw_thw, &run_id) || atw_thw.abort(&run_id);
+                atw_thw.send_response(req_id, &JsValue::from(is_stopped), None);
             },
-            "job-js" => job::run_job_js(jsv, atw_thw, req_id, false),
-            "job-js-async" => job::run_job_js(jsv, atw_thw, req_id, true),
+            "memory-size" => job::run_memory_size(atw_thw, req_id),
+            "thread-destroy" => Self::destroy_thread(),
             _ => {
                 let msg = format!("unknown task: {}", name);
                 debug_ln!("err: {}", &msg);
@@ -68,4 +127,57 @@ impl _Worker {
             },
         }
     }
+
+    fn enqueue(atw_thw: Rc<AtwThreadWorker>, req_id: &str, name: String, jsv: JsValue) {
+        QUEUED.with(|queued| queued.borrow_mut().push_back((req_id.to_string(), name, jsv)));
+        if !IS_DRAINING.with(|is_draining| is_draining.replace(true)) {
+            spawn_local(Self::drain(atw_thw));
+        }
+    }
+
+    // Drop a job not started yet, failing it; returns whether it was found
+    fn dequeue(atw_thw: &AtwThreadWorker, run_id: &str) -> bool {
+        let found = QUEUED.with(|queued| {
+            let mut queued = queued.borrow_mut();
+            let pos = queued.iter().position(|(req_id, _, _)| req_id == run_id);
+            pos.and_then(|pos| queued.remove(pos))
+        });
+        match found {
+            Some(_) => {
+                atw_thw.send_error(run_id, &JsValue::from("Thread: run canceled"));
+                true
+            },
+            None => false,
+        }
+    }
+
+    // Start one job per task when more are queued, so that the messages
+    // received meanwhile, e.g. of `job-cancel`, are handled before the next one
+    async fn drain(atw_thw: Rc<AtwThreadWorker>) {
+        loop {
+            match QUEUED.with(|queued| queued.borrow_mut().pop_front()) {
+                Some((req_id, name, jsv)) => Self::run_job(atw_thw.clone(), &req_id, &name, &jsv),
+                None => break,
+            }
+            if QUEUED.with(|queued| queued.borrow().is_empty()) {
+                break;
+            }
+            utils::yield_task().await;
+        }
+        IS_DRAINING.with(|is_draining| is_draining.set(false));
+    }
+
+    fn run_job(atw_thw: Rc<AtwThreadWorker>, req_id: &str, name: &str, jsv: &JsValue) {
+        type TypeT = Pin<Box<dyn Future<Output = Result<JsValue, JsValue>>>>;
+
+        // TODO - refactor with `enum` later
+        match name {
+            "job-clos" | "job-aclos" => job::Job::<TypeT>::run(jsv, atw_thw, req_id),
+            "job-clos-args" | "job-aclos-args" => job::Job::<TypeT>::run_with_args(jsv, atw_thw, req_id),
+            "job-batch" => job::Job::<TypeT>::run_batch(jsv, atw_thw, req_id),
+            "job-js" => job::run_job_js(jsv, atw_thw, req_id, false),
+            "job-js-async" => job::run_job_js(jsv, atw_thw, req_id, true),
+            _ => unreachable!(),
+        }
+    }
 }
@@ -6,7 +6,7 @@ wasm_bindgen_test_configure!(run_in_browser);
 use wasm_bindgen::prelude::*;
 use wasm_mt::prelude::*;
 use wasm_mt::{Thread, console_ln, utils};
-use wasm_mt_test::{create_mt, get_pkg_js_uri};
+use wasm_mt_test::{create_mt, create_ab_init, get_pkg_js_uri};
 
 async fn create_test_thread() -> Thread {
     let pkg_js_uri = get_pkg_js_uri();
@@ -44,6 +44,18 @@ async fn basics_js() {
     assert_eq!(exec_js_async!(th, js_async).await, ok3);
 }
 
+#[wasm_bindgen_test]
+async fn threads() {
+    let pkg_js_uri = get_pkg_js_uri();
+    let mt = create_mt(&pkg_js_uri).await;
+
+    let ths = mt.threads(3).await.unwrap();
+    assert_eq!(ths.len(), 3);
+    for th in &ths {
+        assert_eq!(exec!(th, move || Ok(JsValue::from(42))).await, Ok(JsValue::from(42)));
+    }
+}
+
 #[wasm_bindgen_test]
 async fn thread() {
     {
@@ -69,3 +81,236 @@ async fn thread() {
         console_ln!("`th` is being dropped!!");
     }
 }
+
+#[wasm_bindgen_test]
+async fn memory_bytes() {
+    let th = create_test_thread().await;
+    let bytes = th.get_memory_bytes().await.unwrap();
+    assert!(bytes > 0.0);
+    assert_eq!(bytes % 65536.0, 0.0); // in wasm pages
+}
+
+static SHARED_COUNT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+
+#[wasm_bindgen_test]
+async fn shared_memory() {
+    use std::sync::atomic::Ordering;
+
+    let mt = WasmMt::new(&get_pkg_js_uri()).with_shared_memory();
+    assert!(mt.is_shared_memory());
+    if !utils::is_shared_memory_available() {
+        // a build without `+atomics`
+        assert!(mt.init().await.is_err());
+        return;
+    }
+
+    let mt = mt.and_init().await.unwrap();
+    mt.set_ab_init(create_ab_init(&get_pkg_js_uri()).await.unwrap());
+    let th = mt.thread().and_init().await.unwrap();
+    assert!(th.is_shared_memory());
+
+    exec!(th, move || {
+        SHARED_COUNT.fetch_add(1, Ordering::SeqCst);
+        Ok(JsValue::NULL)
+    }).await.unwrap();
+    assert_eq!(SHARED_COUNT.load(Ordering::SeqCst), 1);
+}
+
+#[wasm_bindgen_test]
+async fn rayon_pool() {
+    let mt = WasmMt::new(&get_pkg_js_uri()).with_shared_memory();
+    if !utils::is_shared_memory_available() {
+        // a build without `+atomics`
+        return;
+    }
+
+    let mt = mt.and_init().await.unwrap();
+    mt.set_ab_init(create_ab_init(&get_pkg_js_uri()).await.unwrap());
+    let pool = wasm_mt::rayon::thread_pool(&mt, 2).await.unwrap();
+    let th = mt.thread().and_init().await.unwrap();
+
+    // the main thread may not block, so the pool is used from within a job
+    let ptr = &pool as *const rayon::ThreadPool as usize;
+    let result = th.exec({ #[allow(warnings)] {
+        FnOnce!(move || {
+            use rayon::prelude::*;
+
+            let pool = unsafe { &*(ptr as *const rayon::ThreadPool) };
+            let sum: u32 = pool.install(|| (1..=100u32).into_par_iter().sum());
+
+            let (tx, rx) = std::sync::mpsc::channel();
+            pool.spawn(move || tx.send(rayon::current_thread_index()).unwrap());
+            if rx.recv().unwrap().is_none() {
+                return Err(JsValue::from("spawned off the pool"));
+            }
+            Ok(JsValue::from(sum))
+        })
+    }}).await;
+    assert_eq!(result, Ok(JsValue::from(5050)));
+}
+
+#[wasm_bindgen_test]
+async fn sync_primitives() {
+    use wasm_mt::sync::{AtomicCounter, Barrier, Latch, Mutex, Semaphore};
+
+    if utils::run_js("return typeof SharedArrayBuffer;") != Ok(JsValue::from("function")) {
+        return; // not cross-origin isolated
+    }
+
+    let num = 3;
+    let counter = AtomicCounter::new(0);
+    let in_section = AtomicCounter::new(0);
+    let mutex = Mutex::new();
+    let semaphore = Semaphore::new(1);
+    let barrier = Barrier::new(num);
+    let latch = Latch::new(num);
+    assert!(Mutex::from_js(&JsValue::NULL).is_err());
+    assert!(Barrier::from_js(&latch.to_js()).is_err()); // of another length
+    let args = js_sys::Array::new();
+    for jsv in &[counter.to_js(), in_section.to_js(), mutex.to_js(),
+        semaphore.to_js(), barrier.to_js(), latch.to_js()] {
+        args.push(jsv);
+    }
+
+    // All the jobs run at once, contending for each primitive in turn
+    let ths = create_mt(&get_pkg_js_uri()).await.threads(num as usize).await.unwrap();
+    let results = utils::join_all(ths.iter().map(|th| th.exec_with_args(FnOnce!(move |args: Vec<JsValue>| {
+        let counter = AtomicCounter::from_js(&args[0])?;
+        let in_section = AtomicCounter::from_js(&args[1])?;
+        let mutex = Mutex::from_js(&args[2])?;
+        let semaphore = Semaphore::from_js(&args[3])?;
+        let barrier = Barrier::from_js(&args[4])?;
+        let latch = Latch::from_js(&args[5])?;
+        let busy = || utils::run_js("const t = Date.now() + 20; while (Date.now() < t) {}");
+
+        let is_leader = barrier.wait();
+
+        semaphore.acquire();
+        if in_section.inc() != 1 {
+            return Err(JsValue::from("semaphore: more than one permit taken"));
+        }
+        busy()?;
+        in_section.dec();
+        semaphore.release();
+
+        {
+            let _guard = mutex.lock();
+            let val = counter.get();
+            busy()?;
+            counter.set(val + 1); // lost if not exclusive
+        }
+
+        latch.count_down();
+        latch.wait();
+        if latch.count() != 0 {
+            return Err(JsValue::from("latch: released early"));
+        }
+        Ok(JsValue::from(is_leader))
+    }), &args)).collect()).await;
+
+    let leaders = results.into_iter().map(|result| result.unwrap())
+        .filter(|jsv| *jsv == JsValue::TRUE).count();
+    assert_eq!(leaders, 1);
+    assert_eq!(counter.get(), num);
+    assert_eq!(semaphore.available_permits(), 1);
+    assert!(latch.try_wait());
+    assert!(mutex.try_lock().is_some());
+}
+
+#[wasm_bindgen_test]
+async fn spsc_channel() {
+    use wasm_mt::spsc::{channel, Receiver, Sender};
+    use wasm_bindgen_futures::{future_to_promise, JsFuture};
+
+    if utils::run_js("return typeof SharedArrayBuffer;") != Ok(JsValue::from("function")) {
+        return; // not cross-origin isolated
+    }
+
+    let (tx, rx) = channel::<Vec<u32>>(256);
+    assert_eq!(rx.try_recv(), Ok(None));
+
+    let th = create_test_thread().await;
+    let args = js_sys::Array::of1(&tx.into_js());
+    assert!(Sender::<Vec<u32>>::from_js(&JsValue::NULL).is_err());
+    th.exec_with_args(FnOnce!(move |args: Vec<JsValue>| {
+        let tx = Sender::<Vec<u32>>::from_js(&args[0])?;
+        assert!(Sender::<Vec<u32>>::from_js(&args[0]).is_err()); // restored once only
+        for i in 0..3 {
+            tx.send(&vec![i; 4])?;
+        }
+        Ok(JsValue::NULL) // `tx` dropped, closing the channel
+    }), &args).await.unwrap();
+
+    for i in 0..3 {
+        assert_eq!(rx.recv_async().await, Ok(Some(vec![i; 4])));
+    }
+    assert_eq!(rx.recv_async().await, Ok(None));
+
+    // Room for two items only, so that both ends block and wrap around
+    let (tx, rx) = channel::<u32>(16);
+    let ths = create_mt(&get_pkg_js_uri()).await.threads(2).await.unwrap();
+    let ths: Vec<_> = ths.into_iter().map(std::rc::Rc::new).collect();
+    let (producer, args) = (ths[0].clone(), js_sys::Array::of1(&tx.into_js()));
+    let sent = future_to_promise(async move {
+        producer.exec_with_args(FnOnce!(move |args: Vec<JsValue>| {
+            let tx = Sender::<u32>::from_js(&args[0])?;
+            for i in 0..200 {
+                tx.send(&i)?;
+            }
+            Ok(JsValue::NULL)
+        }), &args).await
+    });
+    let sum = ths[1].exec_with_args(FnOnce!(move |args: Vec<JsValue>| {
+        let rx = Receiver::<u32>::from_js(&args[0])?;
+        let mut sum = 0;
+        while let Some(i) = rx.recv()? {
+            sum += i;
+        }
+        Ok(JsValue::from(sum))
+    }), &js_sys::Array::of1(&rx.into_js())).await;
+    assert_eq!(JsFuture::from(sent).await, Ok(JsValue::NULL));
+    assert_eq!(sum, Ok(JsValue::from((0..200).sum::<u32>())));
+}
+
+#[wasm_bindgen_test]
+async fn sync_calls() {
+    use wasm_mt::{sync_call, sync_call_with_timeout};
+
+    if utils::run_js("return typeof SharedArrayBuffer;") != Ok(JsValue::from("function")) {
+        return; // not cross-origin isolated
+    }
+
+    let ths = create_mt(&get_pkg_js_uri()).await.threads(2).await.unwrap();
+    let (handle_a, handle_b) = (ths[0].handle(), ths[1].handle());
+    assert_eq!(ths[0].handle(), handle_a);
+
+    let result = exec!(ths[0], move || {
+        sync_call(&handle_b, FnOnce!(move || Ok(JsValue::from(42))))
+    }).await;
+    assert_eq!(result, Ok(JsValue::from(42)));
+
+    let result = exec!(ths[0], move || {
+        sync_call(&handle_b, FnOnce!(move || Err(js_sys::Error::new("boom").into())))
+    }).await;
+    assert_eq!(result, Err(JsValue::from("Error: boom")));
+
+    let result = exec!(ths[0], move || {
+        sync_call(&handle_a, FnOnce!(move || Ok(JsValue::from(42))))
+    }).await;
+    assert!(result.unwrap_err().as_string().unwrap().contains("deadlock"));
+
+    // a timed-out call no longer counts as blocking its caller
+    let result = exec!(ths[0], move || {
+        sync_call_with_timeout(&handle_b, FnOnce!(move || {
+            utils::run_js("const t = Date.now() + 300; while (Date.now() < t) {}")?;
+            Ok(JsValue::NULL)
+        }), 50.0)
+    }).await;
+    assert_eq!(result, Err(JsValue::from("sync_call(): timed out")));
+    let result = exec!(ths[1], move || {
+        sync_call(&handle_a, FnOnce!(move || Ok(JsValue::from(42))))
+    }).await;
+    assert_eq!(result, Ok(JsValue::from(42)));
+
+    assert!(sync_call(&handle_b, FnOnce!(move || Ok(JsValue::NULL))).is_err()); // not in a worker
+}